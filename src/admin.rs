@@ -0,0 +1,878 @@
+use crate::compatibility::Compatibility;
+use crate::config::{AdminToken, ServerConfig, TokenScope};
+use crate::live_state::{LiveState, Snapshot};
+use crate::metadata::{DEFAULT_CHANNEL, ReleaseDetails, ReleaseStatus};
+use crate::metadata_manager::{DEFAULT_DELTA_PREDECESSORS, MetadataManager, ReleaseOptions};
+use crate::rollout::FailureBudget;
+use crate::signing::{ReleaseSigner, verify_release};
+use crate::upload::{ContentConflict, StoredFile, store_stream};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+use warp::http::StatusCode;
+use warp::multipart::FormData;
+use warp::{Filter, Rejection, Reply};
+
+// Largest image accepted by an upload
+const MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Every /admin endpoint. Each request needs a bearer token from [[admin_tokens]]
+// with the scope the endpoint requires.
+pub fn routes(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    devices(state.clone())
+        .or(releases(state.clone()))
+        .or(kernels(state))
+}
+
+fn authorization() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+}
+
+fn snapshot(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (Arc<Snapshot>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.snapshot())
+}
+
+// The device registry: /admin/devices, /admin/devices/{id}, /admin/releases/stats
+fn devices(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path!("admin" / "devices")
+        .and(warp::get())
+        .and(authorization())
+        .and(snapshot(state.clone()))
+        .and_then(list_devices);
+
+    let events = warp::path!("admin" / "devices" / String)
+        .and(warp::get())
+        .and(authorization())
+        .and(snapshot(state.clone()))
+        .and_then(get_device_events);
+
+    let stats = warp::path!("admin" / "releases" / "stats")
+        .and(warp::get())
+        .and(authorization())
+        .and(snapshot(state))
+        .and_then(get_release_stats);
+
+    list.or(events).or(stats)
+}
+
+// Release management, mirroring the CLI
+fn releases(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path!("admin" / "releases")
+        .and(warp::get())
+        .and(authorization())
+        .and(snapshot(state.clone()))
+        .and_then(list_releases);
+
+    let get = warp::path!("admin" / "releases" / String)
+        .and(warp::get())
+        .and(authorization())
+        .and(snapshot(state.clone()))
+        .and_then(get_release);
+
+    let create = warp::path!("admin" / "releases")
+        .and(warp::post())
+        .and(authorization())
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json::<CreateRelease>())
+        .and(snapshot(state.clone()))
+        .and_then(create_release);
+
+    let promote = warp::path!("admin" / "releases" / String / "promote")
+        .and(warp::post())
+        .and(authorization())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<PromoteRelease>())
+        .and(snapshot(state.clone()))
+        .and_then(promote_release);
+
+    let status = warp::path!("admin" / "releases" / String / "status")
+        .and(warp::put())
+        .and(authorization())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<SetStatus>())
+        .and(snapshot(state.clone()))
+        .and_then(set_release_status);
+
+    let delete = warp::path!("admin" / "releases" / String)
+        .and(warp::delete())
+        .and(authorization())
+        .and(warp::query::<DeleteRelease>())
+        .and(snapshot(state.clone()))
+        .and_then(delete_release);
+
+    let rollback = warp::path!("admin" / "rollback")
+        .and(warp::post())
+        .and(authorization())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<RollbackRequest>())
+        .and(snapshot(state))
+        .and_then(rollback_release);
+
+    list.or(get)
+        .or(create)
+        .or(promote)
+        .or(status)
+        .or(delete)
+        .or(rollback)
+}
+
+// Image uploads: PUT /admin/kernels/{file} with the image as the body, or a
+// multipart POST /admin/kernels with one or more "file" parts
+fn kernels(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let put =
+        warp::path!("admin" / "kernels" / String)
+            .and(warp::put())
+            .and(authorization())
+            .and(snapshot(state.clone()))
+            .and(warp::body::stream())
+            .and_then(
+                |file_name: String,
+                 authorization: Option<String>,
+                 snapshot: Arc<Snapshot>,
+                 body| async move {
+                    let token =
+                        match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+                            Ok(token) => token,
+                            Err(reply) => return Ok::<_, Rejection>(reply),
+                        };
+                    let kernels_dir = Path::new(&snapshot.config.paths.kernels_dir);
+                    let reply =
+                        match store_stream(kernels_dir, &file_name, body, MAX_UPLOAD_SIZE, None)
+                            .await
+                        {
+                            Ok(stored) => upload_reply(&token, vec![stored]),
+                            Err(e) => upload_error(&token, &file_name, e),
+                        };
+                    Ok(reply)
+                },
+            );
+
+    let multipart = warp::path!("admin" / "kernels")
+        .and(warp::post())
+        .and(authorization())
+        .and(snapshot(state))
+        .and(warp::multipart::form().max_length(MAX_UPLOAD_SIZE))
+        .and_then(upload_multipart);
+
+    put.or(multipart)
+}
+
+// Only the sha256 of a token is configured; a presented token is hashed the same way
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// A new random token: 32 bytes from the OS, hex encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("ota_{}", hex)
+}
+
+// The token presented as "Authorization: Bearer <token>", if it grants `scope`
+pub fn authorize(
+    config: &ServerConfig,
+    authorization: Option<String>,
+    scope: TokenScope,
+) -> Result<AdminToken, Box<dyn Reply>> {
+    let presented = authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let Some(presented) = presented else {
+        return Err(unauthorized("Bearer token required"));
+    };
+
+    let digest = hash_token(presented);
+    let Some(token) = config
+        .admin_tokens
+        .iter()
+        .find(|token| token.sha256 == digest)
+    else {
+        warn!("Rejected a request with an unknown token");
+        return Err(unauthorized("Invalid token"));
+    };
+    if !token.allows(scope) {
+        warn!("Admin token {} lacks the {} scope", token.name, scope);
+        return Err(error_reply(
+            StatusCode::FORBIDDEN,
+            &format!("Token lacks the {} scope", scope),
+        ));
+    }
+    Ok(token.clone())
+}
+
+fn unauthorized(message: &str) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_header(
+        error_reply(StatusCode::UNAUTHORIZED, message),
+        "www-authenticate",
+        "Bearer",
+    ))
+}
+
+fn error_reply(status: StatusCode, message: &str) -> Box<dyn Reply> {
+    let error_response = serde_json::json!({ "error": message });
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&error_response),
+        status,
+    ))
+}
+
+fn registry_error() -> Box<dyn Reply> {
+    error_reply(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Error reading device registry",
+    )
+}
+
+async fn list_devices(
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = authorize(&snapshot.config, authorization, TokenScope::Read) {
+        return Ok(reply);
+    }
+    match snapshot.registry.devices().await {
+        Ok(devices) => Ok(Box::new(warp::reply::json(&devices))),
+        Err(_) => Ok(registry_error()),
+    }
+}
+
+async fn get_device_events(
+    device_id: String,
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = authorize(&snapshot.config, authorization, TokenScope::Read) {
+        return Ok(reply);
+    }
+    match snapshot.registry.device_events(&device_id).await {
+        Ok(events) if events.is_empty() => {
+            Ok(error_reply(StatusCode::NOT_FOUND, "Device not found"))
+        }
+        Ok(events) => Ok(Box::new(warp::reply::json(&events))),
+        Err(_) => Ok(registry_error()),
+    }
+}
+
+async fn get_release_stats(
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = authorize(&snapshot.config, authorization, TokenScope::Read) {
+        return Ok(reply);
+    }
+    match snapshot.registry.release_stats().await {
+        Ok(stats) => Ok(Box::new(warp::reply::json(&stats))),
+        Err(_) => Ok(registry_error()),
+    }
+}
+
+// The history as stored, not the server's snapshot, so a change made through
+// this API shows up right away
+async fn list_releases(
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = authorize(&snapshot.config, authorization, TokenScope::Read) {
+        return Ok(reply);
+    }
+    let manager = MetadataManager::new(&snapshot.config);
+    let (history, rollouts) =
+        match tokio::try_join!(manager.list_versions(), manager.load_rollouts()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                return Ok(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &e.to_string(),
+                ));
+            }
+        };
+    Ok(Box::new(warp::reply::json(&serde_json::json!({
+        "latest": history.latest,
+        "channels": history.channels,
+        "pinned": history.pinned,
+        "releases": history.sorted_versions(),
+        "rollouts": rollouts.releases,
+    }))))
+}
+
+async fn get_release(
+    version: String,
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = authorize(&snapshot.config, authorization, TokenScope::Read) {
+        return Ok(reply);
+    }
+    let manager = MetadataManager::new(&snapshot.config);
+    let (history, rollouts) =
+        match tokio::try_join!(manager.list_versions(), manager.load_rollouts()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                return Ok(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &e.to_string(),
+                ));
+            }
+        };
+    match history.find(&version) {
+        Some(kernel) => Ok(Box::new(warp::reply::json(&serde_json::json!({
+            "release": kernel,
+            "rollout": rollouts.releases.get(&version),
+        })))),
+        None => Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            "Kernel version not found",
+        )),
+    }
+}
+
+// A release of an image already in kernels_dir, e.g. one uploaded through
+// /admin/kernels; the same options as `add-kernel`
+#[derive(Debug, Deserialize)]
+pub struct CreateRelease {
+    pub version: String,
+    pub kernel_file: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    #[serde(default)]
+    pub force_latest: bool,
+    #[serde(default)]
+    pub compatibility: Option<Compatibility>,
+    #[serde(default)]
+    pub rollout: Option<u8>,
+    #[serde(default)]
+    pub failure_budget: Option<FailureBudget>,
+    #[serde(default = "default_deltas")]
+    pub deltas: usize,
+    // release_notes, cves, severity, git_commit, build_id and labels
+    #[serde(flatten)]
+    pub details: ReleaseDetails,
+}
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
+}
+
+fn default_deltas() -> usize {
+    DEFAULT_DELTA_PREDECESSORS
+}
+
+async fn create_release(
+    authorization: Option<String>,
+    request: CreateRelease,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let config = &snapshot.config;
+    if let Err(e) = crate::upload::validate_file_name(&request.kernel_file) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e));
+    }
+
+    let signing_keys = match &config.signing {
+        Some(signing) => match ReleaseSigner::load_configured(signing).await {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                warn!("Cannot sign release {}: {}", request.version, e);
+                return Ok(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &e.to_string(),
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let options = ReleaseOptions {
+        channel: request.channel,
+        force_latest: request.force_latest,
+        compatibility: request.compatibility,
+        rollout: request.rollout,
+        failure_budget: request.failure_budget,
+        delta_predecessors: request.deltas,
+        import: false,
+        details: request.details,
+    };
+    let manager = MetadataManager::new(config);
+    let result = manager
+        .add_kernel(
+            request.version.clone(),
+            request.kernel_file,
+            request.description,
+            options,
+            signing_keys.as_ref().map(|(signer, _)| signer),
+        )
+        .await
+        .and_then(|kernel| match &signing_keys {
+            Some((_, public_key)) => verify_release(&kernel, public_key).map(|_| kernel),
+            None => Ok(kernel),
+        });
+    match result {
+        Ok(kernel) => {
+            info!(
+                "Admin token {} published version {} to {}",
+                token.name,
+                kernel.version,
+                kernel.channels.join(", ")
+            );
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&kernel),
+                StatusCode::CREATED,
+            )))
+        }
+        Err(e) => {
+            warn!(
+                "Admin token {} failed to publish version {}: {}",
+                token.name, request.version, e
+            );
+            Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromoteRelease {
+    pub channel: String,
+    #[serde(default)]
+    pub force_latest: bool,
+}
+
+async fn promote_release(
+    version: String,
+    authorization: Option<String>,
+    request: PromoteRelease,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let manager = MetadataManager::new(&snapshot.config);
+    if let Some(reply) = release_missing(&manager, &version).await {
+        return Ok(reply);
+    }
+    match manager
+        .promote(&version, &request.channel, request.force_latest)
+        .await
+    {
+        Ok(kernel) => {
+            info!(
+                "Admin token {} promoted version {} to {}",
+                token.name, version, request.channel
+            );
+            Ok(Box::new(warp::reply::json(&kernel)))
+        }
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetStatus {
+    pub status: ReleaseStatus,
+    // Allow yanking the only remaining stable release
+    #[serde(default)]
+    pub force: bool,
+}
+
+// Yank ({"status": "yanked"}), deprecate ({"status": "deprecated"}) or
+// restore ({"status": "active"}) a release
+async fn set_release_status(
+    version: String,
+    authorization: Option<String>,
+    request: SetStatus,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let manager = MetadataManager::new(&snapshot.config);
+    if let Some(reply) = release_missing(&manager, &version).await {
+        return Ok(reply);
+    }
+    match manager
+        .set_status(&version, request.status, request.force)
+        .await
+    {
+        Ok(kernel) => {
+            info!(
+                "Admin token {} set version {} to {}",
+                token.name, version, request.status
+            );
+            Ok(Box::new(warp::reply::json(&kernel)))
+        }
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteRelease {
+    // Also delete the image and the deltas built for this release
+    #[serde(default)]
+    pub delete_image: bool,
+    // Allow deleting the only remaining stable release
+    #[serde(default)]
+    pub force: bool,
+}
+
+async fn delete_release(
+    version: String,
+    authorization: Option<String>,
+    query: DeleteRelease,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let manager = MetadataManager::new(&snapshot.config);
+    if let Some(reply) = release_missing(&manager, &version).await {
+        return Ok(reply);
+    }
+    match manager
+        .remove_release(&version, query.delete_image, query.force)
+        .await
+    {
+        Ok((kernel, _)) => {
+            info!("Admin token {} deleted version {}", token.name, version);
+            Ok(Box::new(warp::reply::json(&kernel)))
+        }
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RollbackRequest {
+    pub to_version: String,
+    // Every channel of the target release when left out
+    #[serde(default)]
+    pub channel: Option<String>,
+    pub reason: String,
+    // Also yank the releases rolled back from
+    #[serde(default)]
+    pub yank: bool,
+}
+
+// Point channels back at an earlier release; the token's name is recorded
+async fn rollback_release(
+    authorization: Option<String>,
+    request: RollbackRequest,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let manager = MetadataManager::new(&snapshot.config);
+    if let Some(reply) = release_missing(&manager, &request.to_version).await {
+        return Ok(reply);
+    }
+    match manager
+        .rollback(
+            &request.to_version,
+            request.channel.as_deref(),
+            request.yank,
+            &token.name,
+            &request.reason,
+        )
+        .await
+    {
+        Ok(rolled_back) => {
+            let channels: Vec<_> = rolled_back
+                .iter()
+                .map(|(channel, from_version)| {
+                    info!(
+                        "Admin token {} rolled back channel {} from {} to {}: {}",
+                        token.name, channel, from_version, request.to_version, request.reason
+                    );
+                    serde_json::json!({ "channel": channel, "from_version": from_version })
+                })
+                .collect();
+            Ok(Box::new(warp::reply::json(&serde_json::json!({
+                "to_version": request.to_version,
+                "rolled_back": channels,
+            }))))
+        }
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+    }
+}
+
+async fn release_missing(manager: &MetadataManager, version: &str) -> Option<Box<dyn Reply>> {
+    match manager.list_versions().await {
+        Ok(history) if history.find(version).is_some() => None,
+        Ok(_) => Some(error_reply(
+            StatusCode::NOT_FOUND,
+            "Kernel version not found",
+        )),
+        Err(e) => Some(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &e.to_string(),
+        )),
+    }
+}
+
+async fn upload_multipart(
+    authorization: Option<String>,
+    snapshot: Arc<Snapshot>,
+    form: FormData,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let token = match authorize(&snapshot.config, authorization, TokenScope::Publish) {
+        Ok(token) => token,
+        Err(reply) => return Ok(reply),
+    };
+    let kernels_dir = Path::new(&snapshot.config.paths.kernels_dir);
+
+    let mut form = std::pin::pin!(form);
+    let mut stored = Vec::new();
+    while let Some(part) = form.next().await {
+        let part = match part {
+            Ok(part) => part,
+            Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e.to_string())),
+        };
+        if part.name() != "file" {
+            continue;
+        }
+        let Some(file_name) = part.filename().map(str::to_string) else {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "File part has no file name",
+            ));
+        };
+        match store_stream(
+            kernels_dir,
+            &file_name,
+            part.stream(),
+            MAX_UPLOAD_SIZE,
+            None,
+        )
+        .await
+        {
+            Ok(file) => stored.push(file),
+            Err(e) => return Ok(upload_error(&token, &file_name, e)),
+        }
+    }
+    if stored.is_empty() {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "No \"file\" part in the form",
+        ));
+    }
+    Ok(upload_reply(&token, stored))
+}
+
+fn upload_reply(token: &AdminToken, stored: Vec<StoredFile>) -> Box<dyn Reply> {
+    let mut status = StatusCode::OK;
+    for file in &stored {
+        if file.created {
+            status = StatusCode::CREATED;
+            info!(
+                "Admin token {} uploaded {} ({} bytes, {})",
+                token.name, file.file_name, file.file_size, file.checksum
+            );
+        }
+    }
+    Box::new(warp::reply::with_status(warp::reply::json(&stored), status))
+}
+
+fn upload_error(token: &AdminToken, file_name: &str, error: anyhow::Error) -> Box<dyn Reply> {
+    warn!(
+        "Upload of {} by admin token {} failed: {}",
+        file_name, token.name, error
+    );
+    if error.downcast_ref::<ContentConflict>().is_some() {
+        error_reply(StatusCode::CONFLICT, &error.to_string())
+    } else {
+        error_reply(StatusCode::BAD_REQUEST, &error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let read = generate_token();
+        let publish = generate_token();
+        let config = ServerConfig {
+            admin_tokens: vec![
+                AdminToken {
+                    name: "dashboard".to_string(),
+                    sha256: hash_token(&read),
+                    scopes: vec![TokenScope::Read],
+                },
+                AdminToken {
+                    name: "ci".to_string(),
+                    sha256: hash_token(&publish),
+                    scopes: vec![TokenScope::Publish],
+                },
+            ],
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let bearer = |token: &str| Some(format!("Bearer {}", token));
+
+        assert_eq!(
+            authorize(&config, bearer(&read), TokenScope::Read)
+                .ok()
+                .unwrap()
+                .name,
+            "dashboard"
+        );
+        assert!(authorize(&config, bearer(&read), TokenScope::Publish).is_err());
+        // Publish implies read
+        assert_eq!(
+            authorize(&config, bearer(&publish), TokenScope::Read)
+                .ok()
+                .unwrap()
+                .name,
+            "ci"
+        );
+        assert!(authorize(&config, bearer(&publish), TokenScope::Publish).is_ok());
+
+        assert!(authorize(&config, None, TokenScope::Read).is_err());
+        assert!(authorize(&config, Some(read.clone()), TokenScope::Read).is_err());
+        assert!(authorize(&config, bearer(&hash_token(&read)), TokenScope::Read).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_release_routes() {
+        let dir = std::env::temp_dir().join(format!("admin-{}", uuid::Uuid::new_v4()));
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let read = generate_token();
+        let publish = generate_token();
+        let config = ServerConfig {
+            paths: crate::config::Paths {
+                kernels_dir: path("kernels"),
+                metadata_dir: path("metadata"),
+                deltas_dir: path("deltas"),
+                blobs_dir: path("blobs"),
+            },
+            admin_tokens: vec![
+                AdminToken {
+                    name: "dashboard".to_string(),
+                    sha256: hash_token(&read),
+                    scopes: vec![TokenScope::Read],
+                },
+                AdminToken {
+                    name: "ci".to_string(),
+                    sha256: hash_token(&publish),
+                    scopes: vec![TokenScope::Publish],
+                },
+            ],
+            ..Default::default()
+        };
+        config.ensure_directories().await.unwrap();
+        let manager = MetadataManager::new(&config);
+        let state = LiveState::new(
+            &dir.join("server.toml"),
+            config,
+            Arc::new(crate::checksum_cache::ChecksumCache::new()),
+            None,
+        )
+        .await
+        .unwrap();
+        let api = routes(Arc::new(state));
+        let request = |method: &str, path: &str, token: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", format!("Bearer {}", token))
+        };
+
+        // Uploads: new, identical again, and different content under the name
+        for (body, status) in [
+            ("kernel-a", StatusCode::CREATED),
+            ("kernel-a", StatusCode::OK),
+            ("kernel-x", StatusCode::CONFLICT),
+        ] {
+            let reply = request("PUT", "/admin/kernels/a.img", &publish)
+                .body(body)
+                .reply(&api)
+                .await;
+            assert_eq!(reply.status(), status);
+        }
+        let reply = request("PUT", "/admin/kernels/b.img", &read)
+            .body("kernel-b")
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::FORBIDDEN);
+        let reply = request("PUT", "/admin/kernels/b.img", &publish)
+            .body("kernel-b")
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::CREATED);
+
+        let create = |version: &str, file: &str| serde_json::json!({ "version": version, "kernel_file": file, "deltas": 0 });
+        let reply = request("POST", "/admin/releases", &read)
+            .json(&create("1.0.0", "a.img"))
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::FORBIDDEN);
+        for (version, file) in [("1.0.0", "a.img"), ("1.1.0", "b.img")] {
+            let reply = request("POST", "/admin/releases", &publish)
+                .json(&create(version, file))
+                .reply(&api)
+                .await;
+            assert_eq!(reply.status(), StatusCode::CREATED);
+        }
+
+        let reply = request("POST", "/admin/releases/1.1.0/promote", &publish)
+            .json(&serde_json::json!({ "channel": "beta" }))
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let reply = request("PUT", "/admin/releases/1.1.0/status", &publish)
+            .json(&serde_json::json!({ "status": "yanked" }))
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+
+        // 1.0.0 is now the only stable release left
+        let reply = request("PUT", "/admin/releases/1.0.0/status", &publish)
+            .json(&serde_json::json!({ "status": "yanked" }))
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+        let reply = request("DELETE", "/admin/releases/1.0.0", &publish)
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+        let reply = request("DELETE", "/admin/releases/1.0.0?force=true", &publish)
+            .reply(&api)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+
+        let history = manager.list_versions().await.unwrap();
+        let versions: Vec<&str> = history
+            .versions
+            .iter()
+            .map(|v| v.version.as_str())
+            .collect();
+        assert_eq!(versions, ["1.1.0"]);
+        assert_eq!(history.versions[0].channels, ["stable", "beta"]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+// Replace a file so that readers see either the old or the new contents, never
+// a truncated mix, and the new contents survive a crash once this returns:
+// write a temp file in the same directory, fsync it, rename it over the target
+// and fsync the directory so the rename itself is durable.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp_path = temp_path_for(path)?;
+
+    let result = async {
+        let mut file = fs::File::create(&temp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        drop(file);
+        fs::rename(&temp_path, path).await?;
+        sync_parent_dir(path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+fn temp_path_for(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid file path: {}", path.display()))?;
+    Ok(path.with_file_name(format!(
+        ".{}.tmp-{}",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    )))
+}
+
+#[cfg(unix)]
+pub async fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(parent).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Advisory exclusive lock on a file, held until dropped. Serializes writers
+// across processes, e.g. two `add-kernel` runs and a running server.
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+impl FileLock {
+    pub async fn exclusive(path: &Path) -> Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || -> Result<Self> {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.lock()?;
+            Ok(Self { _file: file })
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_atomic_replaces_contents() {
+        let dir = std::env::temp_dir().join(format!("atomic-file-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("version-history.json");
+
+        write_atomic(&path, b"first version, much longer")
+            .await
+            .unwrap();
+        write_atomic(&path, b"second").await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"second");
+
+        // No temp files are left behind
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec!["version-history.json"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
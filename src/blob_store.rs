@@ -0,0 +1,314 @@
+use crate::checksum::calculate_file_checksum;
+use crate::upload::ContentConflict;
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Content-addressed kernel images, stored as <blobs_dir>/sha256/<hex digest>.
+// A blob never changes once written, so identical images are stored once.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobEntry {
+    pub digest: String,
+    pub size: u64,
+}
+
+impl BlobStore {
+    pub fn new<P: AsRef<Path>>(blobs_dir: P) -> Self {
+        Self {
+            root: blobs_dir.as_ref().join("sha256"),
+        }
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        parse_digest(digest).map(|hex| self.root.join(hex))
+    }
+
+    // Copy an image into the store, hashing it while it is copied so the blob
+    // always matches its name. Returns the "sha256:<hex>" digest.
+    pub async fn import(&self, source: &Path) -> Result<String> {
+        fs::create_dir_all(&self.root).await?;
+        let temp_path = self.root.join(format!(".import-{}", uuid::Uuid::new_v4()));
+
+        let hex = match copy_and_hash(source, &temp_path).await {
+            Ok(hex) => hex,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+
+        let blob_path = self.root.join(&hex);
+        if blob_path.exists() {
+            fs::remove_file(&temp_path).await?;
+        } else {
+            fs::rename(&temp_path, &blob_path).await?;
+        }
+        // Also seals a blob stored before blobs were made read-only
+        seal(&blob_path).await?;
+        Ok(format!("sha256:{}", hex))
+    }
+
+    // Make a blob available under another name, e.g. in kernels_dir. Hard links
+    // keep a single copy on disk; across file systems the blob is copied. An
+    // existing file with different content is never replaced (ContentConflict).
+    // The link shares the blob's read-only mode, so the image cannot be
+    // rewritten in place through kernels_dir either.
+    pub async fn link_into(&self, digest: &str, dest: &Path) -> Result<()> {
+        let hex = parse_digest(digest)
+            .ok_or_else(|| anyhow::anyhow!("Invalid blob digest: {}", digest))?;
+        let blob_path = self.root.join(hex);
+        let digest = format!("sha256:{}", hex);
+        if !fs::metadata(&blob_path).await?.permissions().readonly() {
+            return Err(anyhow::anyhow!(
+                "Blob {} is writable, so it may no longer match its digest; import the image again",
+                digest
+            ));
+        }
+        let file_name = dest
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid destination: {}", dest.display()))?
+            .to_string_lossy()
+            .to_string();
+
+        // An identical file is swapped for the link, so the image is stored once
+        let existing = fs::symlink_metadata(dest).await.is_ok();
+        if existing {
+            if same_file(&blob_path, dest).await {
+                return Ok(());
+            }
+            check_identical(dest, &digest, &file_name).await?;
+        }
+
+        // Prepare under a temporary name and move it into place, so the
+        // destination is never missing or half-written
+        let temp_path = dest.with_file_name(format!(".{}.{}", file_name, uuid::Uuid::new_v4()));
+        if fs::hard_link(&blob_path, &temp_path).await.is_err() {
+            fs::copy(&blob_path, &temp_path).await?;
+        }
+        let placed = place(&temp_path, dest, existing, &digest, &file_name).await;
+        let _ = fs::remove_file(&temp_path).await;
+        placed
+    }
+
+    pub async fn list(&self) -> Result<Vec<BlobEntry>> {
+        let mut entries = match fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut blobs = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let digest = format!("sha256:{}", name);
+            if parse_digest(&digest).is_none() {
+                continue;
+            }
+            blobs.push(BlobEntry {
+                digest,
+                size: entry.metadata().await?.len(),
+            });
+        }
+        blobs.sort_by(|a, b| a.digest.cmp(&b.digest));
+        Ok(blobs)
+    }
+
+    pub async fn remove(&self, digest: &str) -> Result<()> {
+        let blob_path = self
+            .blob_path(digest)
+            .ok_or_else(|| anyhow::anyhow!("Invalid blob digest: {}", digest))?;
+        fs::remove_file(blob_path).await?;
+        Ok(())
+    }
+}
+
+// Hex part of a "sha256:<hex>" digest (the prefix is optional). Only the exact
+// form is accepted, since it ends up in file paths.
+pub fn parse_digest(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+    let valid = hex.len() == 64
+        && hex
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    valid.then_some(hex)
+}
+
+async fn copy_and_hash(source: &Path, dest: &Path) -> Result<String> {
+    let mut reader = fs::File::open(source).await?;
+    let mut writer = fs::File::create(dest).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let bytes_read = reader.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        writer.write_all(&buffer[..bytes_read]).await?;
+    }
+    writer.sync_all().await?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Blobs are served as immutable, so nothing may write to them once stored
+async fn seal(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path).await?.permissions();
+    if !permissions.readonly() {
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).await?;
+    }
+    Ok(())
+}
+
+// Move a prepared file into place. A free name is only ever taken, never
+// overwritten, in case a different file appeared there in the meantime.
+async fn place(
+    temp_path: &Path,
+    dest: &Path,
+    existing: bool,
+    digest: &str,
+    file_name: &str,
+) -> Result<()> {
+    if !existing {
+        match fs::hard_link(temp_path, dest).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                check_identical(dest, digest, file_name).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    fs::rename(temp_path, dest).await?;
+    Ok(())
+}
+
+// Only a regular file that already holds the blob's content may be replaced
+async fn check_identical(dest: &Path, digest: &str, file_name: &str) -> Result<()> {
+    if !fs::symlink_metadata(dest).await?.is_file() {
+        return Err(anyhow::anyhow!("{} is not a regular file", dest.display()));
+    }
+    if calculate_file_checksum(dest).await? != digest {
+        return Err(ContentConflict {
+            file_name: file_name.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a).await, fs::metadata(b).await) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+async fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_digest() {
+        let hex = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert_eq!(parse_digest(&format!("sha256:{}", hex)), Some(hex));
+        assert_eq!(parse_digest(hex), Some(hex));
+        assert_eq!(parse_digest(&hex.to_uppercase()), None);
+        assert_eq!(parse_digest("sha256:../../etc/passwd"), None);
+        assert_eq!(parse_digest(&hex[1..]), None);
+    }
+
+    #[tokio::test]
+    async fn test_link_into() {
+        let dir = std::env::temp_dir().join(format!("blob-store-{}", uuid::Uuid::new_v4()));
+        let store = BlobStore::new(dir.join("blobs"));
+        let kernels = dir.join("kernels");
+        fs::create_dir_all(&kernels).await.unwrap();
+        fs::write(dir.join("image"), b"kernel image").await.unwrap();
+        let digest = store.import(&dir.join("image")).await.unwrap();
+
+        store
+            .link_into(&digest, &kernels.join("new.img"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(kernels.join("new.img")).await.unwrap(),
+            b"kernel image"
+        );
+        // Linking again is a no-op, and an identical copy is replaced by the link
+        store
+            .link_into(&digest, &kernels.join("new.img"))
+            .await
+            .unwrap();
+        fs::write(kernels.join("copy.img"), b"kernel image")
+            .await
+            .unwrap();
+        store
+            .link_into(&digest, &kernels.join("copy.img"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(kernels.join("copy.img")).await.unwrap(),
+            b"kernel image"
+        );
+        #[cfg(unix)]
+        assert!(
+            same_file(
+                &store.blob_path(&digest).unwrap(),
+                &kernels.join("copy.img")
+            )
+            .await
+        );
+
+        // Blobs and their links are read-only; a writable blob is refused
+        let blob_path = store.blob_path(&digest).unwrap();
+        for path in [&blob_path, &kernels.join("new.img")] {
+            assert!(fs::metadata(path).await.unwrap().permissions().readonly());
+        }
+        let mut permissions = fs::metadata(&blob_path).await.unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&blob_path, permissions).await.unwrap();
+        assert!(
+            store
+                .link_into(&digest, &kernels.join("late.img"))
+                .await
+                .is_err()
+        );
+        store.import(&dir.join("image")).await.unwrap();
+        store
+            .link_into(&digest, &kernels.join("late.img"))
+            .await
+            .unwrap();
+
+        // A different file under the name is never replaced
+        fs::write(kernels.join("other.img"), b"something else")
+            .await
+            .unwrap();
+        let conflict = store
+            .link_into(&digest, &kernels.join("other.img"))
+            .await
+            .unwrap_err();
+        assert!(conflict.downcast_ref::<ContentConflict>().is_some());
+        assert_eq!(
+            fs::read(kernels.join("other.img")).await.unwrap(),
+            b"something else"
+        );
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
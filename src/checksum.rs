@@ -1,14 +1,19 @@
 use sha2::{Digest, Sha256};
 use std::path::Path;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub async fn calculate_file_checksum<P: AsRef<Path>>(file_path: P) -> Result<String, std::io::Error> {
     let mut file = tokio::fs::File::open(file_path).await?;
+    calculate_reader_checksum(&mut file).await
+}
+
+// Checksum of everything left to read, e.g. from a file handle being served
+pub async fn calculate_reader_checksum<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String, std::io::Error> {
     let mut hasher = Sha256::new();
     let mut buffer = [0u8; 8192]; // 8KB chunks
     
     loop {
-        let bytes_read = file.read(&mut buffer).await?;
+        let bytes_read = reader.read(&mut buffer).await?;
         if bytes_read == 0 {
             break;
         }
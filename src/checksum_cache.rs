@@ -0,0 +1,322 @@
+use crate::checksum::calculate_reader_checksum;
+use crate::metadata::VersionHistory;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, SeekFrom};
+use tracing::{info, warn};
+
+// Identity of a file on disk. A cached checksum is only trusted while all of
+// these still match, so a replaced or rewritten image is always rehashed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileIdentity {
+    size: u64,
+    modified: Option<SystemTime>,
+    inode: u64,
+}
+
+impl FileIdentity {
+    fn from_metadata(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    identity: FileIdentity,
+    checksum: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ChecksumCache {
+    entries: RwLock<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl ChecksumCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Seed the cache with the checksums recorded by `add-kernel`, so the first
+    // download after a restart does not have to hash the image. A file that was
+    // modified after it was published may have been replaced in place, so it is
+    // left to be hashed on first serve.
+    pub async fn seed_from_history(&self, kernels_dir: &Path, history: &VersionHistory) {
+        let mut seeded = 0;
+        for kernel in &history.versions {
+            // Kernel files are served from their canonical path
+            let Ok(path) = tokio::fs::canonicalize(kernels_dir.join(&kernel.kernel_file)).await
+            else {
+                continue;
+            };
+            let Ok(metadata) = tokio::fs::metadata(&path).await else {
+                continue;
+            };
+            if metadata.len() != kernel.file_size {
+                warn!(
+                    "Not seeding checksum for {}: size on disk differs from metadata",
+                    kernel.kernel_file
+                );
+                continue;
+            }
+            let modified_after_publish = metadata.modified().map_or(true, |modified| {
+                DateTime::<Utc>::from(modified) > kernel.release_date
+            });
+            if modified_after_publish {
+                info!(
+                    "Not seeding checksum for {}: modified after it was published",
+                    kernel.kernel_file
+                );
+                continue;
+            }
+            self.insert(
+                &path,
+                FileIdentity::from_metadata(&metadata),
+                &kernel.checksum,
+            );
+            seeded += 1;
+        }
+        info!("Seeded checksum cache with {} entries", seeded);
+    }
+
+    // Return the checksum of the file at `path`, whose handle and metadata the
+    // caller is serving. A miss hashes that handle, so the checksum is always
+    // that of the bytes served even if the path is replaced meanwhile; the
+    // handle is left at the start of the file.
+    pub async fn checksum(
+        &self,
+        path: &Path,
+        file: &mut File,
+        metadata: &Metadata,
+    ) -> std::io::Result<String> {
+        let key = cache_key(path);
+        let identity = FileIdentity::from_metadata(metadata);
+
+        if let Some(entry) = self.entries.read().unwrap().get(&key)
+            && entry.identity == identity
+        {
+            return Ok(entry.checksum.clone());
+        }
+
+        file.seek(SeekFrom::Start(0)).await?;
+        let checksum = calculate_reader_checksum(file).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        self.insert(path, identity, &checksum);
+        Ok(checksum)
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        if self
+            .entries
+            .write()
+            .unwrap()
+            .remove(&cache_key(path))
+            .is_some()
+        {
+            info!("Invalidated cached checksum for {}", path.display());
+        }
+    }
+
+    // Drop cached entries whenever something inside `kernels_dir` changes.
+    // The returned watcher must be kept alive for as long as the cache is used.
+    pub fn watch(self: &Arc<Self>, kernels_dir: &Path) -> Result<RecommendedWatcher> {
+        // Watched through its canonical path, so events name files the way
+        // they are served
+        let kernels_dir = &std::fs::canonicalize(kernels_dir)?;
+        let cache = Arc::clone(self);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    for path in &event.paths {
+                        cache.invalidate(path);
+                    }
+                }
+                Err(e) => warn!("Kernel directory watch error: {}", e),
+            })?;
+        watcher.watch(kernels_dir, RecursiveMode::NonRecursive)?;
+        info!(
+            "Watching {} for kernel image changes",
+            kernels_dir.display()
+        );
+        Ok(watcher)
+    }
+
+    fn insert(&self, path: &Path, identity: FileIdentity, checksum: &str) {
+        self.entries.write().unwrap().insert(
+            cache_key(path),
+            CacheEntry {
+                identity,
+                checksum: checksum.to_string(),
+            },
+        );
+    }
+}
+
+// Paths may be relative to the working directory, so entries are keyed on the
+// absolute path. Kernel files are served, seeded and watched through their
+// canonical path, so those keys agree even when kernels_dir is a symlink.
+fn cache_key(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::calculate_file_checksum;
+    use crate::metadata::KernelInfo;
+    use std::time::Duration;
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    async fn served_checksum(cache: &ChecksumCache, path: &Path) -> String {
+        let mut file = File::open(path).await.unwrap();
+        let metadata = file.metadata().await.unwrap();
+        cache.checksum(path, &mut file, &metadata).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_seed_and_invalidate() {
+        let dir = std::env::temp_dir().join(format!("checksum-cache-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let dir = tokio::fs::canonicalize(&dir).await.unwrap();
+        let published = dir.join("published.img");
+        let replaced = dir.join("replaced.img");
+        tokio::fs::write(&published, b"kernel-a").await.unwrap();
+        tokio::fs::write(&replaced, b"kernel-b").await.unwrap();
+        let published_at = SystemTime::now();
+        set_modified(&published, published_at - Duration::from_secs(60));
+        // Same size, but rewritten after it was published
+        tokio::fs::write(&replaced, b"kernel-c").await.unwrap();
+        set_modified(&replaced, published_at + Duration::from_secs(60));
+
+        let mut history = VersionHistory::empty();
+        for (version, file) in [("1.0.0", "published.img"), ("1.0.1", "replaced.img")] {
+            let mut kernel = KernelInfo::new(
+                version.to_string(),
+                file.to_string(),
+                8,
+                format!("sha256:recorded-{}", version),
+                String::new(),
+            );
+            kernel.release_date = DateTime::<Utc>::from(published_at);
+            history.versions.push(kernel);
+        }
+
+        let cache = ChecksumCache::new();
+        cache.seed_from_history(&dir, &history).await;
+        assert_eq!(
+            served_checksum(&cache, &published).await,
+            "sha256:recorded-1.0.0"
+        );
+        assert_eq!(
+            served_checksum(&cache, &replaced).await,
+            calculate_file_checksum(&replaced).await.unwrap()
+        );
+
+        // A seeded file rewritten in place no longer matches its cached identity
+        tokio::fs::write(&published, b"kernel-d").await.unwrap();
+        set_modified(&published, published_at + Duration::from_secs(120));
+        let actual = calculate_file_checksum(&published).await.unwrap();
+        assert_eq!(served_checksum(&cache, &published).await, actual);
+
+        cache.insert(
+            &published,
+            FileIdentity::from_metadata(&tokio::fs::metadata(&published).await.unwrap()),
+            "sha256:stale",
+        );
+        cache.invalidate(&published);
+        assert_eq!(served_checksum(&cache, &published).await, actual);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_seed_through_symlink() {
+        let dir = std::env::temp_dir().join(format!("checksum-cache-{}", uuid::Uuid::new_v4()));
+        let real = dir.join("real");
+        tokio::fs::create_dir_all(&real).await.unwrap();
+        let real = tokio::fs::canonicalize(&real).await.unwrap();
+        let kernels_dir = dir.join("kernels");
+        std::os::unix::fs::symlink(&real, &kernels_dir).unwrap();
+        tokio::fs::write(real.join("kernel.img"), b"kernel-a")
+            .await
+            .unwrap();
+
+        let mut history = VersionHistory::empty();
+        let mut kernel = KernelInfo::new(
+            "1.0.0".to_string(),
+            "kernel.img".to_string(),
+            8,
+            "sha256:recorded".to_string(),
+            String::new(),
+        );
+        kernel.release_date = Utc::now() + chrono::Duration::minutes(1);
+        history.versions.push(kernel);
+
+        // Seeded through the symlink, found under the canonical path served
+        let cache = ChecksumCache::new();
+        cache.seed_from_history(&kernels_dir, &history).await;
+        assert_eq!(
+            served_checksum(&cache, &real.join("kernel.img")).await,
+            "sha256:recorded"
+        );
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checksum_of_served_handle() {
+        let dir = std::env::temp_dir().join(format!("checksum-cache-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("kernel.img");
+        tokio::fs::write(&path, b"kernel-a").await.unwrap();
+        let expected = calculate_file_checksum(&path).await.unwrap();
+
+        // The path is replaced after the handle was opened
+        let mut file = File::open(&path).await.unwrap();
+        let metadata = file.metadata().await.unwrap();
+        tokio::fs::write(dir.join("new.img"), b"kernel-b")
+            .await
+            .unwrap();
+        tokio::fs::rename(dir.join("new.img"), &path).await.unwrap();
+
+        let cache = ChecksumCache::new();
+        assert_eq!(
+            cache.checksum(&path, &mut file, &metadata).await.unwrap(),
+            expected
+        );
+        let mut served = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut served)
+            .await
+            .unwrap();
+        assert_eq!(served, b"kernel-a");
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+use crate::config::TokenScope;
+use crate::metadata::Severity;
+use crate::metadata_store::StoreBackend;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -8,6 +11,8 @@ pub struct Cli {
     pub command: Commands,
 }
 
+// Parsed once at startup, so the size of AddKernel doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Start the OTA server
@@ -21,12 +26,73 @@ pub enum Commands {
         /// Kernel version (e.g., 1.0.0)
         #[arg(short, long)]
         version: String,
-        /// Kernel file name
-        #[arg(short, long)]
-        file: String,
+        /// Kernel file name; with --from, a template for it ({version}, {channel})
+        #[arg(short, long, required_unless_present = "from")]
+        file: Option<String>,
+        /// Stream the image in from this path ("-" for stdin), stored as --file
+        /// (default "kernel-v{version}.img")
+        #[arg(long, conflicts_with = "import")]
+        from: Option<String>,
+        /// Expected sha256 of the image read with --from (hex, optionally "sha256:"-prefixed)
+        #[arg(long, requires = "from")]
+        sha256: Option<String>,
         /// Description of this version
         #[arg(short, long)]
         description: String,
+        /// Release channel to publish to (e.g., stable, beta, nightly)
+        #[arg(long, default_value = "stable")]
+        channel: String,
+        /// Make this the channel's latest version even if a higher version exists
+        #[arg(long)]
+        force_latest: bool,
+        /// Board/model glob this kernel supports (repeatable, e.g. --board "rev-b*")
+        #[arg(long = "board")]
+        boards: Vec<String>,
+        /// Minimum bootloader version required on the device (semver, e.g. 2.1.0)
+        #[arg(long)]
+        min_bootloader: Option<String>,
+        /// Semver requirement on the version devices upgrade from (e.g. ">=1.0.2")
+        #[arg(long)]
+        requires_from: Option<String>,
+        /// Offer this release to only a percentage of devices at first (0-100)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        rollout: Option<u8>,
+        /// Pause the rollout if more than this percentage of installs fail or roll back (default 2)
+        #[arg(long)]
+        max_failure_percent: Option<f64>,
+        /// Window in minutes over which the failure budget is measured (default 60)
+        #[arg(long)]
+        failure_window: Option<u32>,
+        /// Minimum finished installs before the failure budget applies (default 20)
+        #[arg(long)]
+        min_attempts: Option<u64>,
+        /// Number of preceding releases to precompute binary deltas from (0 disables)
+        #[arg(long, default_value_t = 3)]
+        deltas: usize,
+        /// Treat --file as a path and copy it into the content-addressed blob store
+        #[arg(long)]
+        import: bool,
+        /// Markdown file with the release notes
+        #[arg(long)]
+        release_notes: Option<String>,
+        /// CVE fixed by this release (repeatable, e.g. --cve CVE-2024-1086)
+        #[arg(long = "cve")]
+        cves: Vec<String>,
+        /// How urgently devices should install this release
+        #[arg(long, value_enum)]
+        severity: Option<Severity>,
+        /// Git commit the image was built from
+        #[arg(long)]
+        git_commit: Option<String>,
+        /// Build system ID of the image
+        #[arg(long)]
+        build_id: Option<String>,
+        /// Free-form label (repeatable, e.g. --label toolchain=gcc-13)
+        #[arg(long = "label", value_parser = parse_label)]
+        labels: Vec<(String, String)>,
+        /// TOML file with release notes and build details (fields as above; flags take precedence)
+        #[arg(long)]
+        details: Option<String>,
         /// Configuration file path
         #[arg(short, long, default_value = "config/server.toml")]
         config: String,
@@ -37,4 +103,170 @@ pub enum Commands {
         #[arg(short, long, default_value = "config/server.toml")]
         config: String,
     },
+    /// Promote an existing kernel version to the head of a channel
+    Promote {
+        /// Kernel version to promote
+        #[arg(short, long)]
+        version: String,
+        /// Target channel
+        #[arg(long)]
+        channel: String,
+        /// Make this the channel's latest version even if a higher version exists
+        #[arg(long)]
+        force_latest: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Set the staged rollout percentage of a kernel version
+    Rollout {
+        /// Kernel version
+        #[arg(short, long)]
+        version: String,
+        /// Percentage of devices the release is offered to (0-100)
+        #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Resume a rollout that was paused for exceeding its failure budget
+    Resume {
+        /// Kernel version
+        #[arg(short, long)]
+        version: String,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Stop offering a kernel version; devices already running it can still download it
+    Yank {
+        /// Kernel version
+        #[arg(short, long)]
+        version: String,
+        /// Allow yanking the only remaining stable release
+        #[arg(long)]
+        force: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Keep offering a kernel version, flagged as deprecated
+    Deprecate {
+        /// Kernel version
+        #[arg(short, long)]
+        version: String,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Delete a kernel version from the metadata
+    Remove {
+        /// Kernel version
+        #[arg(short, long)]
+        version: String,
+        /// Also delete the image (unless another release uses it) and its deltas
+        #[arg(long)]
+        delete_image: bool,
+        /// Allow removing the only remaining stable release
+        #[arg(long)]
+        force: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Make an earlier kernel version the offered one again
+    Rollback {
+        /// Kernel version to roll back to
+        #[arg(long)]
+        to: String,
+        /// Only roll back this channel (default: every channel of the target version)
+        #[arg(long)]
+        channel: Option<String>,
+        /// Why the rollback is needed, recorded with the release rolled back from
+        #[arg(short, long)]
+        reason: String,
+        /// Who is rolling back (default: $USER)
+        #[arg(long)]
+        by: Option<String>,
+        /// Also yank the versions rolled back from
+        #[arg(long)]
+        yank: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Rebuild a corrupt or missing version history from surviving metadata and kernels_dir
+    Repair {
+        /// Write the rebuilt history (quarantining the corrupt file) instead of only previewing it
+        #[arg(long)]
+        write: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Copy all release metadata from one storage backend to another
+    MigrateStore {
+        /// Backend to read from
+        #[arg(long, value_enum)]
+        from: StoreBackend,
+        /// Backend to write to
+        #[arg(long, value_enum)]
+        to: StoreBackend,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Remove blobs that no release references
+    Gc {
+        /// Only list the blobs that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Show devices and their reported install status
+    Devices {
+        /// Show the full event log of a single device
+        #[arg(short, long)]
+        device: Option<String>,
+        /// Show per-release install success rates instead of devices
+        #[arg(long)]
+        stats: bool,
+        /// Configuration file path
+        #[arg(short, long, default_value = "config/server.toml")]
+        config: String,
+    },
+    /// Generate an Ed25519 release signing key pair
+    Keygen {
+        /// Private key output path (the public key is written next to it with a .pub suffix)
+        #[arg(short, long, default_value = "config/signing.key")]
+        out: String,
+    },
+    /// Generate a self-signed TLS certificate and key for lab setups
+    GenSelfSigned {
+        /// Directory to write cert.pem and key.pem to
+        #[arg(short, long, default_value = "config/tls")]
+        out_dir: String,
+        /// Host names and IP addresses the certificate is valid for
+        #[arg(long = "hostname", default_values_t = ["localhost".to_string()])]
+        hostnames: Vec<String>,
+    },
+    /// Generate an /admin API token and print the hashed entry for server.toml
+    HashToken {
+        /// Name shown in the logs for changes made with this token
+        #[arg(short, long)]
+        name: String,
+        /// What the token may do (repeatable; publish implies read and report)
+        #[arg(long = "scope", value_enum, default_values_t = [TokenScope::Read])]
+        scopes: Vec<TokenScope>,
+    },
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", label)),
+    }
 }
@@ -0,0 +1,193 @@
+use crate::versioning::{compare_versions, parse_version};
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// Hardware and upgrade-path constraints a device must meet to install a release
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Compatibility {
+    // Board/model globs, e.g. "rev-b*" ('*' and '?' wildcards). Empty means any board.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub boards: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_bootloader: Option<String>,
+    // Semver requirement on the version a device upgrades from, e.g. ">=1.0.2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_from: Option<String>,
+}
+
+// What a device tells us about itself. Anything unknown is `None`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceProfile<'a> {
+    pub device_id: Option<&'a str>,
+    pub hardware_model: Option<&'a str>,
+    pub bootloader_version: Option<&'a str>,
+    pub current_version: Option<&'a str>,
+}
+
+impl Compatibility {
+    pub fn is_empty(&self) -> bool {
+        self.boards.is_empty() && self.min_bootloader.is_none() && self.requires_from.is_none()
+    }
+
+    // Reject constraints that could never be evaluated
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(requirement) = &self.requires_from {
+            VersionReq::parse(requirement).map_err(|e| {
+                format!("Invalid upgrade path requirement '{}': {}", requirement, e)
+            })?;
+        }
+        // Bootloader versions are compared by semver precedence, so a minimum
+        // that doesn't parse would reject every device
+        if let Some(min_bootloader) = &self.min_bootloader {
+            parse_version(min_bootloader).map_err(|e| {
+                format!(
+                    "Invalid minimum bootloader version '{}': {}",
+                    min_bootloader, e
+                )
+            })?;
+        }
+        if self.boards.iter().any(|board| board.trim().is_empty()) {
+            return Err("Board patterns must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    // Check a device against these constraints. A device that does not report
+    // a value a constraint depends on is treated as not eligible.
+    pub fn check(&self, device: &DeviceProfile) -> Result<(), String> {
+        if !self.boards.is_empty() {
+            let Some(model) = device.hardware_model else {
+                return Err("Release is restricted to specific boards".to_string());
+            };
+            if !self.boards.iter().any(|pattern| glob_match(pattern, model)) {
+                return Err(format!("Hardware model '{}' is not supported", model));
+            }
+        }
+
+        if let Some(min_bootloader) = &self.min_bootloader {
+            let Some(bootloader) = device.bootloader_version else {
+                return Err(format!("Requires bootloader {} or newer", min_bootloader));
+            };
+            if compare_versions(bootloader, min_bootloader) == Ordering::Less {
+                return Err(format!(
+                    "Bootloader {} is older than required {}",
+                    bootloader, min_bootloader
+                ));
+            }
+        }
+
+        if let Some(requirement) = &self.requires_from {
+            let requirement = VersionReq::parse(requirement)
+                .map_err(|_| format!("Invalid upgrade path requirement '{}'", requirement))?;
+            let current = device.current_version.and_then(|v| parse_version(v).ok());
+            match current {
+                Some(current) if requirement.matches(&current) => {}
+                _ => {
+                    return Err(format!(
+                        "Upgrade requires current version matching '{}'",
+                        requirement
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Case-insensitive glob match supporting '*' (any run) and '?' (one character)
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("rev-b*", "rev-b2"));
+        assert!(glob_match("rev-?", "REV-C"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*-lab-*", "rev-a-lab-01"));
+        assert!(!glob_match("rev-b*", "rev-a"));
+        assert!(!glob_match("rev-?", "rev-10"));
+    }
+
+    #[test]
+    fn test_check_compatibility() {
+        let compatibility = Compatibility {
+            boards: vec!["rev-b*".to_string(), "rev-c".to_string()],
+            min_bootloader: Some("2.1.0".to_string()),
+            requires_from: Some(">=1.0.2".to_string()),
+        };
+        let device = DeviceProfile {
+            device_id: Some("device-1"),
+            hardware_model: Some("rev-b2"),
+            bootloader_version: Some("2.3.0"),
+            current_version: Some("1.0.2"),
+        };
+        assert!(compatibility.check(&device).is_ok());
+
+        let wrong_board = DeviceProfile {
+            hardware_model: Some("rev-a"),
+            ..device
+        };
+        assert!(compatibility.check(&wrong_board).is_err());
+
+        let old_bootloader = DeviceProfile {
+            bootloader_version: Some("2.0.9"),
+            ..device
+        };
+        assert!(compatibility.check(&old_bootloader).is_err());
+
+        let too_old = DeviceProfile {
+            current_version: Some("1.0.0"),
+            ..device
+        };
+        assert!(compatibility.check(&too_old).is_err());
+
+        assert!(compatibility.validate().is_ok());
+        for min_bootloader in ["2.1", "latest", ""] {
+            let invalid = Compatibility {
+                min_bootloader: Some(min_bootloader.to_string()),
+                ..Default::default()
+            };
+            assert!(
+                invalid.validate().is_err(),
+                "{:?} was accepted",
+                min_bootloader
+            );
+        }
+
+        assert!(compatibility.check(&DeviceProfile::default()).is_err());
+        assert!(
+            Compatibility::default()
+                .check(&DeviceProfile::default())
+                .is_ok()
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::metadata_store::StoreBackend;
+use crate::safe_path::SymlinkPolicy;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
@@ -5,7 +7,23 @@ use std::path::Path;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub server: Server,
+    // Sockets to serve on, each with its own routes. Without any, a single
+    // listener on server.host:server.port serves every route.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<Listener>,
     pub paths: Paths,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub serving: Serving,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing: Option<Signing>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+    // Bearer tokens accepted by the /admin API and, with the report scope, by
+    // device event reports; without any the admin API refuses every request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub admin_tokens: Vec<AdminToken>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,10 +32,152 @@ pub struct Server {
     pub port: u16,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Listener {
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "all_route_groups")]
+    pub routes: Vec<RouteGroup>,
+    // Serve HTTPS when [tls] is configured; set to false for a plain HTTP listener
+    #[serde(default = "default_true")]
+    pub tls: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Sets of endpoints a listener can serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteGroup {
+    // Everything devices use: version checks, downloads, event reports
+    Device,
+    // /admin endpoints
+    Admin,
+}
+
+impl std::fmt::Display for RouteGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteGroup::Device => write!(f, "device"),
+            RouteGroup::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+fn all_route_groups() -> Vec<RouteGroup> {
+    vec![RouteGroup::Device, RouteGroup::Admin]
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Paths {
     pub kernels_dir: String,
     pub metadata_dir: String,
+    #[serde(default = "default_deltas_dir")]
+    pub deltas_dir: String,
+    #[serde(default = "default_blobs_dir")]
+    pub blobs_dir: String,
+}
+
+fn default_deltas_dir() -> String {
+    "./deltas".to_string()
+}
+
+fn default_blobs_dir() -> String {
+    "./blobs".to_string()
+}
+
+// Backend for the version history and rollout state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Storage {
+    #[serde(default)]
+    pub backend: StoreBackend,
+    // Database file for the sqlite backend; defaults to metadata_dir/metadata.db
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sqlite_path: Option<String>,
+}
+
+// Which files under kernels_dir are served at /kernels
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Serving {
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    // Also serve files no release references, e.g. images staged by hand
+    #[serde(default)]
+    pub serve_unlisted: bool,
+}
+
+// An /admin API token. Only its sha256 is stored, as printed by `ota-server hash-token`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminToken {
+    // Shown in logs, so it is clear who made a change
+    pub name: String,
+    pub sha256: String,
+    pub scopes: Vec<TokenScope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    // List releases, devices and install statistics
+    Read,
+    // Upload images and create, promote, yank and delete releases; implies read
+    // and report
+    Publish,
+    // Report install events for devices without a client certificate
+    Report,
+}
+
+impl std::fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenScope::Read => write!(f, "read"),
+            TokenScope::Publish => write!(f, "publish"),
+            TokenScope::Report => write!(f, "report"),
+        }
+    }
+}
+
+impl AdminToken {
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|granted| *granted == scope || *granted == TokenScope::Publish)
+    }
+}
+
+// Ed25519 release signing keys, as generated by `ota-server keygen`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signing {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+// Server certificate for HTTPS, in PEM. Reloaded when the files change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    // Intermediate certificates sent after the server certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_chain: Option<String>,
+    // Verify device certificates against a device CA (mutual TLS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuth>,
+}
+
+// Device CA for client certificates, in PEM. A device's certificate names it:
+// the subject CN, or the first DNS/URI alternative name, is its device ID.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientAuth {
+    pub ca: String,
+    // Certificate revocation list(s) from the device CA, in PEM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crl: Option<String>,
+    // Refuse devices without a certificate; when false one is only verified if presented
+    #[serde(default = "default_true")]
+    pub required: bool,
 }
 
 impl ServerConfig {
@@ -27,9 +187,112 @@ impl ServerConfig {
         Ok(config)
     }
 
+    pub fn serves_tls(&self, listener: &Listener) -> bool {
+        listener.tls && self.tls.is_some()
+    }
+
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.listeners.is_empty() {
+            vec![Listener {
+                host: self.server.host.clone(),
+                port: self.server.port,
+                routes: all_route_groups(),
+                tls: true,
+            }]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    // Catch settings that parse but cannot work, before they replace a running config
+    pub fn validate(&self) -> Result<()> {
+        if self.server.host.trim().is_empty() {
+            return Err(anyhow::anyhow!("server.host must not be empty"));
+        }
+        let listeners = self.listeners();
+        for (index, listener) in listeners.iter().enumerate() {
+            if listener.host.trim().is_empty() {
+                return Err(anyhow::anyhow!("Listener {} has an empty host", index + 1));
+            }
+            if listener.routes.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Listener {}:{} serves no routes",
+                    listener.host,
+                    listener.port
+                ));
+            }
+            if listeners[..index]
+                .iter()
+                .any(|other| other.host == listener.host && other.port == listener.port)
+            {
+                return Err(anyhow::anyhow!(
+                    "Listener {}:{} is configured twice",
+                    listener.host,
+                    listener.port
+                ));
+            }
+            // Plain HTTP would let devices in without the certificate that is
+            // meant to be required of them
+            let requires_certificates = self
+                .tls
+                .as_ref()
+                .and_then(|tls| tls.client_auth.as_ref())
+                .is_some_and(|client_auth| client_auth.required);
+            if requires_certificates
+                && !listener.tls
+                && listener.routes.contains(&RouteGroup::Device)
+            {
+                return Err(anyhow::anyhow!(
+                    "Listener {}:{} serves device routes without TLS, but [tls.client_auth] requires device certificates",
+                    listener.host,
+                    listener.port
+                ));
+            }
+        }
+        let paths = [
+            ("kernels_dir", &self.paths.kernels_dir),
+            ("metadata_dir", &self.paths.metadata_dir),
+            ("deltas_dir", &self.paths.deltas_dir),
+            ("blobs_dir", &self.paths.blobs_dir),
+        ];
+        for (name, path) in paths {
+            if path.trim().is_empty() {
+                return Err(anyhow::anyhow!("paths.{} must not be empty", name));
+            }
+        }
+        for (index, token) in self.admin_tokens.iter().enumerate() {
+            let is_digest = token.sha256.len() == 64
+                && token
+                    .sha256
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+            if !is_digest {
+                return Err(anyhow::anyhow!(
+                    "Admin token {} must have a lowercase hex sha256",
+                    token.name
+                ));
+            }
+            if token.scopes.is_empty() {
+                return Err(anyhow::anyhow!("Admin token {} has no scopes", token.name));
+            }
+            if self.admin_tokens[..index]
+                .iter()
+                .any(|other| other.name == token.name)
+            {
+                return Err(anyhow::anyhow!(
+                    "Admin token {} is configured twice",
+                    token.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub async fn ensure_directories(&self) -> Result<(), std::io::Error> {
         tokio::fs::create_dir_all(&self.paths.kernels_dir).await?;
         tokio::fs::create_dir_all(&self.paths.metadata_dir).await?;
+        tokio::fs::create_dir_all(&self.paths.deltas_dir).await?;
+        tokio::fs::create_dir_all(&self.paths.blobs_dir).await?;
         Ok(())
     }
 }
@@ -41,10 +304,56 @@ impl Default for ServerConfig {
                 host: "0.0.0.0".to_string(),
                 port: 8080,
             },
+            listeners: Vec::new(),
             paths: Paths {
                 kernels_dir: "./kernels".to_string(),
                 metadata_dir: "./metadata".to_string(),
+                deltas_dir: default_deltas_dir(),
+                blobs_dir: default_blobs_dir(),
             },
+            storage: Storage::default(),
+            serving: Serving::default(),
+            signing: None,
+            tls: None,
+            admin_tokens: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_device_listener_with_required_certificates() {
+        let mut config = ServerConfig {
+            tls: Some(Tls {
+                cert: "cert.pem".to_string(),
+                key: "key.pem".to_string(),
+                ca_chain: None,
+                client_auth: Some(ClientAuth {
+                    ca: "device-ca.pem".to_string(),
+                    crl: None,
+                    required: true,
+                }),
+            }),
+            listeners: vec![Listener {
+                host: "127.0.0.1".to_string(),
+                port: 8080,
+                routes: vec![RouteGroup::Device],
+                tls: false,
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        // Fine for admin routes, or when certificates are optional
+        config.listeners[0].routes = vec![RouteGroup::Admin];
+        assert!(config.validate().is_ok());
+        config.listeners[0].routes = vec![RouteGroup::Device];
+        if let Some(client_auth) = config.tls.as_mut().and_then(|tls| tls.client_auth.as_mut()) {
+            client_auth.required = false;
         }
+        assert!(config.validate().is_ok());
     }
 }
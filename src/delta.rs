@@ -0,0 +1,69 @@
+use anyhow::Result;
+use std::io::{Read, Write};
+
+// zstd "patch-from" deltas: the target image compressed with the source image as
+// reference prefix. Apply with `zstd -d --long=<window_log> --patch-from=<source>`.
+pub const DELTA_ALGORITHM: &str = "zstd-patch";
+
+// Deltas are built once per release, so spend the CPU on the smallest output
+const COMPRESSION_LEVEL: i32 = 19;
+
+// Smallest and largest window logs zstd accepts
+const MIN_WINDOW_LOG: u32 = 10;
+const MAX_WINDOW_LOG: u32 = 31;
+
+pub fn delta_file_name(from_version: &str, to_version: &str) -> String {
+    format!("{}-to-{}.zst", from_version, to_version)
+}
+
+// The window must cover both images so matches anywhere in the source are usable
+pub fn window_log(source_len: usize, target_len: usize) -> u32 {
+    let size = source_len.max(target_len).max(1) as u64;
+    let log = u64::BITS - (size - 1).leading_zeros();
+    log.clamp(MIN_WINDOW_LOG, MAX_WINDOW_LOG)
+}
+
+pub fn create_delta(source: &[u8], target: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder =
+        zstd::stream::Encoder::with_ref_prefix(Vec::new(), COMPRESSION_LEVEL, source)?;
+    encoder.window_log(window_log(source.len(), target.len()))?;
+    encoder.long_distance_matching(true)?;
+    encoder.include_checksum(true)?;
+    encoder.set_pledged_src_size(Some(target.len() as u64))?;
+    encoder.write_all(target)?;
+    Ok(encoder.finish()?)
+}
+
+pub fn apply_delta(source: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = zstd::stream::Decoder::with_ref_prefix(delta, source)?;
+    decoder.window_log_max(MAX_WINDOW_LOG)?;
+    let mut target = Vec::new();
+    decoder.read_to_end(&mut target)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let source: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| (i * 7).to_le_bytes())
+            .collect();
+        let mut target = source.clone();
+        target[1000..1016].copy_from_slice(b"patched kernel!!");
+        target.extend_from_slice(b"new trailing section");
+
+        let delta = create_delta(&source, &target).unwrap();
+        assert!(delta.len() < target.len() / 100);
+        assert_eq!(apply_delta(&source, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn test_window_log() {
+        assert_eq!(window_log(0, 0), 10);
+        assert_eq!(window_log(1 << 20, 1000), 20);
+        assert_eq!(window_log(1000, (1 << 20) + 1), 21);
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+// Lifecycle of an update on a device, as reported by the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallStatus {
+    Downloading,
+    Downloaded,
+    Installing,
+    Installed,
+    BootedOk,
+    RolledBack,
+    Failed,
+}
+
+// Body of POST /devices/{device_id}/events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventReport {
+    pub version: String,
+    pub status: InstallStatus,
+    #[serde(default)]
+    pub error_code: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEvent {
+    pub device_id: String,
+    pub version: String,
+    pub status: InstallStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    // Reported with a client certificate or a report token. Only events logged
+    // before reports needed either lack it.
+    #[serde(
+        default,
+        alias = "verified",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub authenticated: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub device_id: String,
+    pub version: String,
+    pub status: InstallStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    pub last_seen: DateTime<Utc>,
+}
+
+// Install outcomes for one release, counting each device's latest attempt once
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseStats {
+    pub in_progress: u64,
+    pub booted_ok: u64,
+    pub rolled_back: u64,
+    pub failed: u64,
+    pub success_rate: Option<f64>,
+}
+
+impl EventReport {
+    // For a report the caller has authenticated
+    pub fn into_event(self, device_id: &str) -> Result<DeviceEvent, String> {
+        validate_device_id(device_id)?;
+        if self.version.trim().is_empty() {
+            return Err("version is required".to_string());
+        }
+        if self.status == InstallStatus::Failed && self.error_code.is_none() {
+            return Err("error_code is required for failed installs".to_string());
+        }
+        Ok(DeviceEvent {
+            device_id: device_id.to_string(),
+            version: self.version,
+            status: self.status,
+            error_code: self.error_code,
+            message: self.message,
+            timestamp: Utc::now(),
+            authenticated: true,
+        })
+    }
+}
+
+pub fn validate_device_id(device_id: &str) -> Result<(), String> {
+    let valid = !device_id.trim().is_empty()
+        && device_id.len() <= 128
+        && !device_id.chars().any(char::is_control);
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid device id: {:?}", device_id))
+    }
+}
+
+// Size at which the event log is rotated. The previous log is kept, so at most
+// twice this is on disk and statistics still cover the recent past.
+const MAX_LOG_SIZE: u64 = 64 * 1024 * 1024;
+
+// Append-only log of device events, stored as JSON lines under metadata_dir/devices.
+// The events are also kept in memory once read, so queries don't reread the log.
+pub struct DeviceRegistry {
+    events_path: PathBuf,
+    max_log_size: u64,
+    // None until the log is first read
+    events: Mutex<Option<Vec<DeviceEvent>>>,
+}
+
+impl DeviceRegistry {
+    pub fn new<P: AsRef<Path>>(metadata_dir: P) -> Self {
+        Self {
+            events_path: metadata_dir.as_ref().join("devices").join("events.jsonl"),
+            max_log_size: MAX_LOG_SIZE,
+            events: Mutex::new(None),
+        }
+    }
+
+    // The log rotated out of the way when the current one filled up
+    fn rotated_path(&self) -> PathBuf {
+        self.events_path.with_extension("jsonl.1")
+    }
+
+    pub async fn record(&self, event: &DeviceEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let mut events = self.events.lock().await;
+        if let Some(parent) = self.events_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if let Ok(metadata) = tokio::fs::metadata(&self.events_path).await
+            && metadata.len() + line.len() as u64 > self.max_log_size
+        {
+            tokio::fs::rename(&self.events_path, self.rotated_path()).await?;
+            // The oldest events are gone from disk; reread what is left when next needed
+            *events = None;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.events_path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        if let Some(events) = events.as_mut() {
+            events.push(event.clone());
+        }
+        Ok(())
+    }
+
+    // Run `f` over every retained event, oldest first. The log can be large,
+    // so callers aggregate or filter in place rather than copying it.
+    pub async fn with_events<T>(&self, f: impl FnOnce(&[DeviceEvent]) -> T) -> Result<T> {
+        let mut cached = self.events.lock().await;
+        if cached.is_none() {
+            let mut events = Vec::new();
+            for path in [self.rotated_path(), self.events_path.clone()] {
+                read_events(&path, &mut events).await?;
+            }
+            *cached = Some(events);
+        }
+        Ok(f(cached.as_deref().unwrap_or_default()))
+    }
+
+    pub async fn device_events(&self, device_id: &str) -> Result<Vec<DeviceEvent>> {
+        self.with_events(|events| {
+            events
+                .iter()
+                .filter(|event| event.device_id == device_id)
+                .cloned()
+                .collect()
+        })
+        .await
+    }
+
+    pub async fn devices(&self) -> Result<Vec<DeviceSummary>> {
+        self.with_events(summarize_devices).await
+    }
+
+    pub async fn release_stats(&self) -> Result<BTreeMap<String, ReleaseStats>> {
+        self.with_events(release_stats).await
+    }
+}
+
+async fn read_events(path: &Path, events: &mut Vec<DeviceEvent>) -> Result<()> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // A torn final line from a crash must not hide every other event
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<DeviceEvent>(line) {
+            Ok(event) => events.push(event),
+            Err(e) => warn!(
+                "Skipping malformed device event at {}:{}: {}",
+                path.display(),
+                index + 1,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+// Latest known state of every device
+pub fn summarize_devices(events: &[DeviceEvent]) -> Vec<DeviceSummary> {
+    let mut latest: BTreeMap<&str, &DeviceEvent> = BTreeMap::new();
+    for event in events {
+        latest.insert(&event.device_id, event);
+    }
+    latest
+        .into_values()
+        .map(|event| DeviceSummary {
+            device_id: event.device_id.clone(),
+            version: event.version.clone(),
+            status: event.status,
+            error_code: event.error_code.clone(),
+            last_seen: event.timestamp,
+        })
+        .collect()
+}
+
+pub fn release_stats(events: &[DeviceEvent]) -> BTreeMap<String, ReleaseStats> {
+    // Most recent status of each (device, release) pair
+    let mut attempts: HashMap<(&str, &str), InstallStatus> = HashMap::new();
+    for event in events {
+        attempts.insert((&event.device_id, &event.version), event.status);
+    }
+
+    let mut stats: BTreeMap<String, ReleaseStats> = BTreeMap::new();
+    for ((_, version), status) in attempts {
+        let entry = stats.entry(version.to_string()).or_default();
+        match status {
+            InstallStatus::BootedOk => entry.booted_ok += 1,
+            InstallStatus::RolledBack => entry.rolled_back += 1,
+            InstallStatus::Failed => entry.failed += 1,
+            _ => entry.in_progress += 1,
+        }
+    }
+
+    for entry in stats.values_mut() {
+        let finished = entry.booted_ok + entry.rolled_back + entry.failed;
+        entry.success_rate = (finished > 0).then(|| entry.booted_ok as f64 / finished as f64);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(device_id: &str, version: &str, status: InstallStatus) -> DeviceEvent {
+        DeviceEvent {
+            device_id: device_id.to_string(),
+            version: version.to_string(),
+            status,
+            error_code: None,
+            message: None,
+            timestamp: Utc::now(),
+            authenticated: true,
+        }
+    }
+
+    #[test]
+    fn test_release_stats() {
+        let events = vec![
+            event("a", "2.0.0", InstallStatus::Downloading),
+            event("a", "2.0.0", InstallStatus::BootedOk),
+            event("b", "2.0.0", InstallStatus::Installing),
+            event("b", "2.0.0", InstallStatus::RolledBack),
+            event("c", "2.0.0", InstallStatus::Failed),
+            event("c", "2.0.0", InstallStatus::BootedOk),
+            event("d", "2.0.0", InstallStatus::Downloaded),
+        ];
+        let stats = release_stats(&events);
+        let stats = &stats["2.0.0"];
+
+        assert_eq!(stats.booted_ok, 2);
+        assert_eq!(stats.rolled_back, 1);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(stats.in_progress, 1);
+        assert_eq!(stats.success_rate, Some(2.0 / 3.0));
+
+        let devices = summarize_devices(&events);
+        assert_eq!(devices.len(), 4);
+        assert_eq!(devices[1].status, InstallStatus::RolledBack);
+    }
+
+    #[tokio::test]
+    async fn test_log_rotation() {
+        let dir = std::env::temp_dir().join(format!("device-registry-{}", uuid::Uuid::new_v4()));
+        let mut registry = DeviceRegistry::new(&dir);
+        let line_size = serde_json::to_vec(&event("a", "1.0.0", InstallStatus::Installed))
+            .unwrap()
+            .len() as u64
+            + 1;
+        registry.max_log_size = line_size * 2;
+
+        for device_id in ["a", "b", "c", "d", "e"] {
+            registry
+                .record(&event(device_id, "1.0.0", InstallStatus::Installed))
+                .await
+                .unwrap();
+        }
+
+        // Only the current and the previous log are kept
+        let devices: Vec<String> = registry
+            .with_events(|events| events.iter().map(|event| event.device_id.clone()).collect())
+            .await
+            .unwrap();
+        assert_eq!(devices, ["c", "d", "e"]);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,40 +1,448 @@
-use crate::checksum::calculate_file_checksum;
-use crate::config::ServerConfig;
-use crate::metadata::KernelInfo;
-use std::path::PathBuf;
-use tracing::info;
+use crate::admin;
+use crate::blob_store::BlobStore;
+use crate::checksum_cache::ChecksumCache;
+use crate::compatibility::DeviceProfile;
+use crate::config::{RouteGroup, ServerConfig, TokenScope};
+use crate::device_registry::{EventReport, InstallStatus};
+use crate::live_state::{LiveState, Snapshot};
+use crate::metadata::{DEFAULT_CHANNEL, KernelInfo, ReleaseSignature};
+use crate::range::{RangeRequest, http_date, if_range_matches, parse_range};
+use crate::safe_path::{PathRejection, decode_file_name, resolve_in};
+use crate::signing::{key_fingerprint, public_key_base64};
+use crate::tls::ClientIdentity;
+use crate::update_check::{CheckRequest, check_for_update, must_downgrade, select_release};
+use serde::Deserialize;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
 use warp::{Filter, Rejection, Reply};
 
 // Health check endpoint
-pub fn health() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+pub fn health() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("health").and(warp::get()).map(|| {
         info!("Health check request received");
         warp::reply::json(&serde_json::json!({"status": "healthy"}))
     })
 }
 
+// Every endpoint in the given route groups, for one listener
+pub fn routes(
+    groups: Vec<RouteGroup>,
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let device = route_group(groups.contains(&RouteGroup::Device)).and(
+        version(state.clone())
+            .or(version_details(state.clone()))
+            .or(channel_version(state.clone()))
+            .or(check(state.clone()))
+            .or(device_events(state.clone()))
+            .or(signing_key(state.clone()))
+            .or(deltas(state.clone()))
+            .or(blobs(state.clone()))
+            .or(kernels(state.clone())),
+    );
+    let admin = route_group(groups.contains(&RouteGroup::Admin)).and(admin::routes(state));
+
+    health().or(device).or(admin)
+}
+
+// Passes requests through only if the listener serves this route group
+fn route_group(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
 // Version info endpoint
 pub fn version(
-    config: ServerConfig,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("version")
         .and(warp::get())
-        .and(warp::any().map(move || config.clone()))
-        .and_then(get_latest_version)
+        .and(warp::query::<VersionQuery>())
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and(warp::any().map(move || state.snapshot()))
+        .and_then(
+            |query: VersionQuery, identity: Option<ClientIdentity>, snapshot: Arc<Snapshot>| {
+                let channel = query
+                    .channel
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+                get_channel_version(channel, query, identity, snapshot)
+            },
+        )
+}
+
+// Full metadata of one release, including its release notes: /versions/{version}
+pub fn version_details(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("versions" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || state.snapshot()))
+        .and_then(get_version_details)
+}
+
+// Per-channel version info endpoint: /channels/{name}/version
+pub fn channel_version(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("channels" / String / "version")
+        .and(warp::get())
+        .and(warp::query::<VersionQuery>())
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and(warp::any().map(move || state.snapshot()))
+        .and_then(get_channel_version)
+}
+
+// Device-aware update check endpoint
+pub fn check(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("check")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<CheckRequest>())
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and(warp::any().map(move || state.snapshot()))
+        .and_then(check_update)
+}
+
+// Optional device details; releases with compatibility constraints are only
+// offered when the device supplies the details they depend on
+#[derive(Debug, Deserialize)]
+pub struct VersionQuery {
+    pub channel: Option<String>,
+    pub device_id: Option<String>,
+    pub board: Option<String>,
+    pub bootloader: Option<String>,
+    pub current_version: Option<String>,
+}
+
+impl VersionQuery {
+    fn device_profile(&self) -> DeviceProfile<'_> {
+        DeviceProfile {
+            device_id: self.device_id.as_deref(),
+            hardware_model: self.board.as_deref(),
+            bootloader_version: self.bootloader.as_deref(),
+            current_version: self.current_version.as_deref(),
+        }
+    }
+}
+
+// Device install-status reporting endpoint
+pub fn device_events(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("devices" / String / "events")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json::<EventReport>())
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::any().map(move || state.clone()))
+        .and_then(record_device_event)
+}
+
+// Release signing public key endpoint, so devices can pin the key offline
+pub fn signing_key(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("signing-key")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || -> Box<dyn Reply> {
+            info!("Signing key request received");
+            match &state.snapshot().public_key {
+                Some(key) => Box::new(warp::reply::json(&serde_json::json!({
+                    "algorithm": "ed25519",
+                    "public_key": public_key_base64(key),
+                    "key_fingerprint": key_fingerprint(key),
+                }))),
+                None => Box::new(warp::reply::with_status(
+                    warp::reply::json(
+                        &serde_json::json!({"error": "Release signing is not configured"}),
+                    ),
+                    warp::http::StatusCode::NOT_FOUND,
+                )),
+            }
+        })
+}
+
+// Binary delta endpoint, /deltas/{from}/{to}
+pub fn deltas(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let checksum_cache = state.checksum_cache();
+    warp::path!("deltas" / String / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::any().map(move || state.snapshot()))
+        .and(warp::any().map(move || checksum_cache.clone()))
+        .and_then(serve_delta_file)
+}
+
+// Content-addressed image endpoint, /blobs/sha256/{digest}
+pub fn blobs(
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let checksum_cache = state.checksum_cache();
+    warp::path!("blobs" / "sha256" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::any().map(move || state.snapshot()))
+        .and(warp::any().map(move || checksum_cache.clone()))
+        .and_then(serve_blob)
 }
 
 // Kernel file serving endpoint
 pub fn kernels(
-    config: ServerConfig,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    state: Arc<LiveState>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let checksum_cache = state.checksum_cache();
     warp::path("kernels")
         .and(warp::get())
         .and(warp::path::param::<String>())
-        .and(warp::any().map(move || config.clone()))
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and(warp::any().map(move || state.snapshot()))
+        .and(warp::any().map(move || checksum_cache.clone()))
         .and_then(serve_kernel_file)
 }
 
-async fn get_latest_version(config: ServerConfig) -> Result<Box<dyn Reply>, Rejection> {
+async fn get_channel_version(
+    channel: String,
+    mut query: VersionQuery,
+    identity: Option<ClientIdentity>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    info!("Version check request received for channel: {}", channel);
+
+    query.device_id = match authenticated_device_id(query.device_id.as_deref(), &identity) {
+        Ok(device_id) => device_id,
+        Err(reply) => return Ok(reply),
+    };
+
+    let Some(metadata) = &snapshot.metadata else {
+        return Ok(metadata_error());
+    };
+    let history = &metadata.history;
+
+    // Deployments that only have latest.json keep working
+    if history.versions.is_empty() && channel == DEFAULT_CHANNEL {
+        return get_latest_version(&snapshot.config).await;
+    }
+
+    let rollouts = &metadata.rollouts;
+
+    match select_release(history, rollouts, &channel, &query.device_profile()) {
+        Ok(kernel_info) => {
+            info!(
+                "Returning version info for channel {}: {}",
+                channel, kernel_info.version
+            );
+            let mut client_info = kernel_info.to_client_format();
+            client_info.force_downgrade =
+                query
+                    .current_version
+                    .as_deref()
+                    .is_some_and(|current_version| {
+                        must_downgrade(history, &channel, current_version, kernel_info)
+                    });
+            Ok(Box::new(warp::reply::json(&client_info)))
+        }
+        Err(reason) => {
+            info!("No release offered on channel {}: {}", channel, reason);
+            let error_response = serde_json::json!({ "error": reason });
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::NOT_FOUND,
+            )))
+        }
+    }
+}
+
+async fn get_version_details(
+    version: String,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    info!("Version details request received: {}", version);
+    let Some(metadata) = &snapshot.metadata else {
+        return Ok(metadata_error());
+    };
+
+    // "v1.2.0" and "1.2.0" both find a release
+    match metadata.history.find(&version) {
+        Some(kernel_info) => Ok(Box::new(warp::reply::json(kernel_info))),
+        None => {
+            let error_response = serde_json::json!({"error": "Kernel version not found"});
+            Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::NOT_FOUND,
+            )))
+        }
+    }
+}
+
+async fn check_update(
+    mut request: CheckRequest,
+    identity: Option<ClientIdentity>,
+    snapshot: Arc<Snapshot>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    request.device_id = match authenticated_device_id(Some(&request.device_id), &identity) {
+        Ok(device_id) => device_id.unwrap_or_default(),
+        Err(reply) => return Ok(reply),
+    };
+    info!(
+        "Update check from device {} (version {}, model {}, channel {})",
+        request.device_id, request.current_version, request.hardware_model, request.channel
+    );
+
+    if request.device_id.trim().is_empty() {
+        let error_response = serde_json::json!({"error": "device_id is required"});
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&error_response),
+            warp::http::StatusCode::BAD_REQUEST,
+        )));
+    }
+
+    let Some(metadata) = &snapshot.metadata else {
+        return Ok(metadata_error());
+    };
+    let history = &metadata.history;
+
+    let rollouts = &metadata.rollouts;
+
+    let response = check_for_update(history, rollouts, &request);
+    info!(
+        "Update check result for {}: {:?}",
+        request.device_id, response
+    );
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn record_device_event(
+    device_id: String,
+    report: EventReport,
+    identity: Option<ClientIdentity>,
+    authorization: Option<String>,
+    state: Arc<LiveState>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let snapshot = state.snapshot();
+    // Events feed the failure budget, so they are only taken from devices that
+    // proved who they are, or that hold a token allowed to report for them
+    if identity.is_none()
+        && let Err(reply) = admin::authorize(&snapshot.config, authorization, TokenScope::Report)
+    {
+        return Ok(reply);
+    }
+    if let Err(reply) = authenticated_device_id(Some(&device_id), &identity) {
+        return Ok(reply);
+    }
+    let Some(metadata) = &snapshot.metadata else {
+        return Ok(metadata_error());
+    };
+    let registry = &snapshot.registry;
+    // Recorded under the release's own version, however the device spelled it
+    let event = report.into_event(&device_id).and_then(|mut event| {
+        match metadata.history.find(&event.version) {
+            Some(kernel) => {
+                event.version = kernel.version.clone();
+                Ok(event)
+            }
+            None => Err(format!("Unknown kernel version: {}", event.version)),
+        }
+    });
+    let event = match event {
+        Ok(event) => event,
+        Err(reason) => {
+            let error_response = serde_json::json!({ "error": reason });
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::BAD_REQUEST,
+            )));
+        }
+    };
+
+    info!(
+        "Device {} reported {:?} for version {}",
+        event.device_id, event.status, event.version
+    );
+
+    if registry.record(&event).await.is_err() {
+        let error_response = serde_json::json!({"error": "Error recording device event"});
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&error_response),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )));
+    }
+
+    // Checked in the background, so a device isn't kept waiting on it
+    if matches!(
+        event.status,
+        InstallStatus::Failed | InstallStatus::RolledBack
+    ) {
+        state.check_failure_budget(&event.version);
+    }
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"status": "recorded"})),
+        warp::http::StatusCode::ACCEPTED,
+    )))
+}
+
+// A device that presented a client certificate is the device it names. Requests
+// without a device ID get that one; requests claiming another are refused.
+fn authenticated_device_id(
+    claimed: Option<&str>,
+    identity: &Option<ClientIdentity>,
+) -> Result<Option<String>, Box<dyn Reply>> {
+    let Some(identity) = identity else {
+        return Ok(claimed.map(str::to_string));
+    };
+    match claimed {
+        Some(claimed) if !claimed.trim().is_empty() && claimed != identity.device_id => {
+            warn!(
+                "Device {} claimed to be device {}",
+                identity.device_id, claimed
+            );
+            let error_response = serde_json::json!({
+                "error": "device_id does not match the client certificate"
+            });
+            Err(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::FORBIDDEN,
+            )))
+        }
+        _ => Ok(Some(identity.device_id.clone())),
+    }
+}
+
+fn metadata_error() -> Box<dyn Reply> {
+    let error_response = serde_json::json!({"error": "Invalid metadata format"});
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&error_response),
+        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+    ))
+}
+
+async fn get_latest_version(config: &ServerConfig) -> Result<Box<dyn Reply>, Rejection> {
     info!("Version check request received");
     let metadata_path = PathBuf::from(&config.paths.metadata_dir).join("latest.json");
 
@@ -68,22 +476,192 @@ async fn get_latest_version(config: ServerConfig) -> Result<Box<dyn Reply>, Reje
 
 async fn serve_kernel_file(
     filename: String,
-    config: ServerConfig,
+    range: Option<String>,
+    if_range: Option<String>,
+    identity: Option<ClientIdentity>,
+    snapshot: Arc<Snapshot>,
+    checksum_cache: Arc<ChecksumCache>,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    info!("Kernel file request received: {}", filename);
-    let file_path = PathBuf::from(&config.paths.kernels_dir).join(&filename);
+    let config = &snapshot.config;
+    match &identity {
+        Some(identity) => info!(
+            "Kernel file request received from device {}: {}",
+            identity.device_id, filename
+        ),
+        None => info!("Kernel file request received: {}", filename),
+    }
+    let kernels_dir = PathBuf::from(&config.paths.kernels_dir);
+    let served = match decode_file_name(&filename) {
+        Ok(name) if config.serving.serve_unlisted || is_published(&snapshot, &name).await => {
+            resolve_in(&kernels_dir, &name, config.serving.symlinks)
+                .await
+                .map(|resolved| (name, resolved))
+        }
+        Ok(_) => Err(PathRejection::Unlisted),
+        Err(rejection) => Err(rejection),
+    };
+    let (filename, resolved) = match served {
+        Ok(served) => served,
+        Err(PathRejection::Invalid) => {
+            warn!(
+                "Refused kernel file request for {:?}: invalid name",
+                filename
+            );
+            let error_response = serde_json::json!({"error": "Invalid file name"});
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::BAD_REQUEST,
+            )));
+        }
+        Err(rejection) => {
+            match rejection {
+                PathRejection::NotFound => info!("Kernel file not found: {:?}", filename),
+                _ => warn!(
+                    "Refused kernel file request for {:?}: {}",
+                    filename, rejection
+                ),
+            }
+            let error_response = serde_json::json!({"error": "File not found"});
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::NOT_FOUND,
+            )));
+        }
+    };
+
+    // Detached image signature of the release these exact bytes belong to
+    let headers = |checksum: &str| match find_image_signature(&snapshot, &filename, checksum) {
+        Some(signature) => vec![
+            ("x-signature", signature.image_signature),
+            ("x-signature-key", signature.key_fingerprint),
+        ],
+        None => Vec::new(),
+    };
+
+    serve_opened(
+        &resolved.path,
+        (resolved.file, resolved.metadata),
+        range,
+        if_range,
+        &checksum_cache,
+        headers,
+    )
+    .await
+}
 
-    if !file_path.exists() {
-        info!("Kernel file not found: {}", filename);
-        let error_response = serde_json::json!({"error": "File not found"});
+async fn serve_delta_file(
+    from_version: String,
+    to_version: String,
+    range: Option<String>,
+    if_range: Option<String>,
+    snapshot: Arc<Snapshot>,
+    checksum_cache: Arc<ChecksumCache>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let config = &snapshot.config;
+    info!("Delta request received: {} -> {}", from_version, to_version);
+
+    let Some(metadata) = &snapshot.metadata else {
+        return Ok(metadata_error());
+    };
+    let history = &metadata.history;
+    let deltas_dir = PathBuf::from(&config.paths.deltas_dir);
+    let Some((target, delta)) = history
+        .find(&to_version)
+        .and_then(|target| Some((target, target.find_delta(&from_version)?)))
+        .filter(|(_, delta)| deltas_dir.join(&delta.delta_file).exists())
+    else {
+        info!("Delta not found: {} -> {}", from_version, to_version);
+        let error_response = serde_json::json!({"error": "Delta not found"});
         return Ok(Box::new(warp::reply::with_status(
             warp::reply::json(&error_response),
             warp::http::StatusCode::NOT_FOUND,
         )));
-    }
+    };
+
+    let file_path = deltas_dir.join(&delta.delta_file);
+    let headers = |_: &str| {
+        vec![
+            ("x-delta-algorithm", delta.algorithm.clone()),
+            ("x-delta-window-log", delta.window_log.to_string()),
+            ("x-base-checksum", delta.source_checksum.clone()),
+            ("x-target-checksum", target.checksum.clone()),
+        ]
+    };
+
+    serve_file(&file_path, range, if_range, &checksum_cache, headers).await
+}
 
-    // Calculate checksum for verification
-    let checksum = match calculate_file_checksum(&file_path).await {
+async fn serve_blob(
+    digest: String,
+    range: Option<String>,
+    if_range: Option<String>,
+    snapshot: Arc<Snapshot>,
+    checksum_cache: Arc<ChecksumCache>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let config = &snapshot.config;
+    info!("Blob request received: {}", digest);
+
+    let blob_path = BlobStore::new(&config.paths.blobs_dir)
+        .blob_path(&digest)
+        .filter(|path| path.exists());
+    let Some(blob_path) = blob_path else {
+        info!("Blob not found: {}", digest);
+        let error_response = serde_json::json!({"error": "Blob not found"});
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&error_response),
+            warp::http::StatusCode::NOT_FOUND,
+        )));
+    };
+
+    // A blob's content is fixed by its name
+    let headers = |_: &str| {
+        vec![(
+            "cache-control",
+            "public, max-age=31536000, immutable".to_string(),
+        )]
+    };
+
+    serve_file(&blob_path, range, if_range, &checksum_cache, headers).await
+}
+
+// Stream a file with validators and single-range support. Extra headers are
+// chosen once the checksum of the bytes being served is known.
+async fn serve_file(
+    file_path: &Path,
+    range: Option<String>,
+    if_range: Option<String>,
+    checksum_cache: &ChecksumCache,
+    headers: impl FnOnce(&str) -> Vec<(&'static str, String)>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let opened = match open_with_metadata(file_path).await {
+        Ok(opened) => opened,
+        Err(_) => {
+            let error_response = serde_json::json!({"error": "Error reading file"});
+            return Ok(Box::new(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )));
+        }
+    };
+    serve_opened(file_path, opened, range, if_range, checksum_cache, headers).await
+}
+
+// Serve a file that is already open, e.g. one whose handle was checked
+async fn serve_opened(
+    file_path: &Path,
+    (mut file, file_metadata): (File, std::fs::Metadata),
+    range: Option<String>,
+    if_range: Option<String>,
+    checksum_cache: &ChecksumCache,
+    headers: impl FnOnce(&str) -> Vec<(&'static str, String)>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let filename = file_path.display();
+
+    // Cached checksum, keyed on the identity of the file handle being served
+    let checksum = match checksum_cache
+        .checksum(file_path, &mut file, &file_metadata)
+        .await
+    {
         Ok(hash) => hash,
         Err(_) => {
             let error_response = serde_json::json!({"error": "Error calculating checksum"});
@@ -94,22 +672,88 @@ async fn serve_kernel_file(
         }
     };
 
-    match tokio::fs::read(&file_path).await {
-        Ok(contents) => {
+    let file_size = file_metadata.len();
+    let etag = format!("\"{}\"", checksum.trim_start_matches("sha256:"));
+    let last_modified = file_metadata.modified().map(http_date).unwrap_or_default();
+
+    // A stale If-Range validator means the client must start over from byte zero
+    let range_request = match (&range, &if_range) {
+        (Some(range), Some(validator)) if !if_range_matches(validator, &etag, &last_modified) => {
+            info!(
+                "If-Range validator does not match, ignoring range: {}",
+                range
+            );
+            RangeRequest::Full
+        }
+        (Some(range), _) => parse_range(range, file_size),
+        (None, _) => RangeRequest::Full,
+    };
+
+    let mut builder = Response::builder()
+        .header("accept-ranges", "bytes")
+        .header("etag", &etag)
+        .header("last-modified", &last_modified)
+        .header("x-checksum", &checksum);
+
+    for (name, value) in headers(&checksum) {
+        builder = builder.header(name, value);
+    }
+
+    let response = match range_request {
+        RangeRequest::Full => {
             info!(
-                "Serving kernel file: {} ({} bytes, checksum: {})",
+                "Serving file: {} ({} bytes, checksum: {})",
+                filename, file_size, checksum
+            );
+            builder
+                .status(StatusCode::OK)
+                .header("content-type", "application/octet-stream")
+                .header("content-length", file_size)
+                .body(Body::wrap_stream(ReaderStream::new(file)))
+        }
+        RangeRequest::Partial(byte_range) => {
+            if file.seek(SeekFrom::Start(byte_range.start)).await.is_err() {
+                let error_response = serde_json::json!({"error": "Error reading file"});
+                return Ok(Box::new(warp::reply::with_status(
+                    warp::reply::json(&error_response),
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )));
+            }
+            info!(
+                "Serving file: {} (range {}, checksum: {})",
                 filename,
-                contents.len(),
+                byte_range.content_range(file_size),
                 checksum
             );
-            Ok(Box::new(warp::reply::with_header(
-                warp::reply::with_header(contents, "content-type", "application/octet-stream"),
-                "x-checksum",
-                checksum,
-            )))
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("content-type", "application/octet-stream")
+                .header("content-length", byte_range.len())
+                .header("content-range", byte_range.content_range(file_size))
+                .body(Body::wrap_stream(ReaderStream::new(
+                    file.take(byte_range.len()),
+                )))
+        }
+        RangeRequest::MultipleRanges | RangeRequest::Unsatisfiable => {
+            let message = if range_request == RangeRequest::MultipleRanges {
+                "Multiple ranges are not supported"
+            } else {
+                "Requested range not satisfiable"
+            };
+            info!("Rejecting range request for {}: {}", filename, message);
+            let error_response = serde_json::json!({"error": message});
+            builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("content-type", "application/json")
+                .header("content-range", format!("bytes */{}", file_size))
+                .body(Body::from(error_response.to_string()))
         }
+    };
+
+    match response {
+        Ok(response) => Ok(Box::new(response)),
         Err(_) => {
-            let error_response = serde_json::json!({"error": "Error reading file"});
+            let error_response = serde_json::json!({"error": "Error building response"});
             Ok(Box::new(warp::reply::with_status(
                 warp::reply::json(&error_response),
                 warp::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -117,3 +761,46 @@ async fn serve_kernel_file(
         }
     }
 }
+
+// Whether a release references this image. Deployments that only have
+// latest.json serve the image it names.
+async fn is_published(snapshot: &Snapshot, filename: &str) -> bool {
+    if let Some(metadata) = &snapshot.metadata
+        && !metadata.history.versions.is_empty()
+    {
+        return metadata
+            .history
+            .versions
+            .iter()
+            .any(|kernel| kernel.kernel_file == filename);
+    }
+    let latest_path = PathBuf::from(&snapshot.config.paths.metadata_dir).join("latest.json");
+    match tokio::fs::read_to_string(&latest_path).await {
+        Ok(content) => serde_json::from_str::<KernelInfo>(&content)
+            .is_ok_and(|kernel_info| kernel_info.kernel_file == filename),
+        Err(_) => false,
+    }
+}
+
+// Image signatures cover the file digest, so any signed release of this file
+// will do, as long as its checksum is that of the bytes being served
+fn find_image_signature(
+    snapshot: &Snapshot,
+    filename: &str,
+    checksum: &str,
+) -> Option<ReleaseSignature> {
+    snapshot
+        .metadata
+        .as_ref()?
+        .history
+        .versions
+        .iter()
+        .filter(|kernel| kernel.kernel_file == filename && kernel.checksum == checksum)
+        .find_map(|kernel| kernel.signature.clone())
+}
+
+async fn open_with_metadata(path: &Path) -> std::io::Result<(File, std::fs::Metadata)> {
+    let file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok((file, metadata))
+}
@@ -0,0 +1,196 @@
+use crate::tls::{CertStore, ClientIdentity, client_identity};
+use anyhow::Result;
+use socket2::{Domain, Socket, Type};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+use warp::http::{Request, Response};
+use warp::hyper::Body;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{Service, service_fn};
+use warp::{Filter, Rejection, Reply};
+
+// Clients that connect but never finish the handshake are dropped after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Addresses to bind for a configured host. IP literals are used as-is (IPv6
+// optionally in brackets); names such as "localhost" are resolved and every
+// address they resolve to is bound.
+pub async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let literal = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot resolve listener host {}: {}", host, e))?
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("Listener host {} has no addresses", host));
+    }
+    Ok(addrs)
+}
+
+// Bind a listening socket. The IPv6 wildcard "::" is always dual-stack, so it
+// also accepts IPv4 connections whatever the system default is.
+pub fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let IpAddr::V6(ip) = addr.ip() {
+        socket.set_only_v6(!ip.is_unspecified())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("Cannot bind {}: {}", addr, e))?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// Serve the routes on a bound socket, over TLS when given the certificate store.
+// Requests on a connection whose client certificate names a device carry its
+// ClientIdentity, for handlers to pick up with warp::ext. Once `stop` fires (or
+// its sender is dropped) the socket is closed; connections already accepted are
+// served to completion.
+pub async fn serve<F, R>(
+    listener: TcpListener,
+    cert_store: Option<Arc<CertStore>>,
+    routes: F,
+    mut stop: oneshot::Receiver<()>,
+) where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let service = warp::service(routes);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stop => return,
+        };
+        let (tcp, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let service = service.clone();
+        // Taken per connection, so a renewed certificate or CRL applies right away
+        let acceptor = cert_store.as_ref().map(|cert_store| cert_store.acceptor());
+        tokio::spawn(async move {
+            let Some(acceptor) = acceptor else {
+                serve_connection(tcp, peer, service, None).await;
+                return;
+            };
+
+            // A failed handshake only drops this connection
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) if is_client_certificate_error(&e) => {
+                    info!("Rejected client certificate from {}: {}", peer, e);
+                    return;
+                }
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+
+            let identity = match stream.get_ref().1.peer_certificates() {
+                Some([certificate, ..]) => match client_identity(certificate) {
+                    Ok(identity) => {
+                        debug!("Device {} connected from {}", identity.device_id, peer);
+                        Some(identity)
+                    }
+                    Err(e) => {
+                        info!("Rejected client certificate from {}: {}", peer, e);
+                        return;
+                    }
+                },
+                _ => None,
+            };
+            serve_connection(stream, peer, service, identity).await;
+        });
+    }
+}
+
+async fn serve_connection<IO, S>(
+    io: IO,
+    peer: SocketAddr,
+    service: S,
+    identity: Option<ClientIdentity>,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let service = service_fn(move |mut request: Request<Body>| {
+        if let Some(identity) = &identity {
+            request.extensions_mut().insert(identity.clone());
+        }
+        service.clone().call(request)
+    });
+    if let Err(e) = Http::new().serve_connection(io, service).await {
+        debug!("Connection from {} closed: {}", peer, e);
+    }
+}
+
+fn is_client_certificate_error(error: &std::io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_literals() {
+        assert_eq!(
+            resolve("192.168.10.1", 8080).await.unwrap(),
+            vec!["192.168.10.1:8080".parse().unwrap()]
+        );
+        assert_eq!(
+            resolve("[::1]", 8443).await.unwrap(),
+            vec!["[::1]:8443".parse().unwrap()]
+        );
+        assert_eq!(
+            resolve("::", 80).await.unwrap(),
+            vec!["[::]:80".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() {
+        let listener = bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // An IPv4 client reaches the IPv6 wildcard listener
+        let connect = tokio::net::TcpStream::connect(("127.0.0.1", port));
+        let (accepted, connected) = tokio::join!(listener.accept(), connect);
+        assert!(accepted.is_ok() && connected.is_ok());
+    }
+}
@@ -0,0 +1,796 @@
+use crate::checksum_cache::ChecksumCache;
+use crate::config::{Listener, RouteGroup, ServerConfig};
+use crate::device_registry::DeviceRegistry;
+use crate::handlers;
+use crate::listener;
+use crate::metadata::VersionHistory;
+use crate::metadata_manager::MetadataManager;
+use crate::metadata_store::{StoreBackend, open_store};
+use crate::rollout::RolloutState;
+use crate::signing::load_public_key;
+use crate::tls::CertStore;
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+// Wait this long after a change before reloading, so a burst of writes (a
+// publish touches several files) results in a single reload
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// File watches can miss changes (e.g. on network file systems, or when the
+// watched directory is replaced), so modification times are also compared
+// this often
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Everything a request needs, loaded together. Requests hold on to the
+// snapshot they started with, so a reload never affects in-flight downloads.
+pub struct Snapshot {
+    pub config: ServerConfig,
+    pub public_key: Option<VerifyingKey>,
+    pub registry: Arc<DeviceRegistry>,
+    // None when the metadata could not be loaded at startup
+    pub metadata: Option<Arc<MetadataSnapshot>>,
+}
+
+pub struct MetadataSnapshot {
+    pub history: VersionHistory,
+    pub rollouts: RolloutState,
+}
+
+// A socket being served
+struct RunningListener {
+    listener: Listener,
+    tls: bool,
+    addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    // The accept loop; connections it accepted run in tasks of their own
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl RunningListener {
+    // Close the socket. Connections already accepted on it are served to completion.
+    async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+        info!("Stopped listening on {}", self.addr);
+    }
+}
+
+// A socket started by `apply_listeners`
+pub struct StartedListener {
+    pub addr: SocketAddr,
+    pub tls: bool,
+    pub routes: Vec<RouteGroup>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Reload {
+    Config,
+    Certificates,
+    Metadata,
+}
+
+// The current snapshot of a running server, swapped atomically whenever
+// server.toml or the metadata changes (or on SIGHUP)
+pub struct LiveState {
+    config_path: PathBuf,
+    current: RwLock<Arc<Snapshot>>,
+    checksum_cache: Arc<ChecksumCache>,
+    // Certificate of the HTTPS listeners, if any
+    cert_store: Option<Arc<CertStore>>,
+    watchers: Mutex<Vec<RecommendedWatcher>>,
+    listeners: tokio::sync::Mutex<Vec<RunningListener>>,
+    // Files as of the last reload, to spot changes the watches missed
+    stamps: Mutex<BTreeMap<Reload, Vec<FileStamp>>>,
+    // Releases whose failure budget is due a check, after a reported failure
+    budget_checks: mpsc::UnboundedSender<String>,
+    budget_checks_rx: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+}
+
+impl LiveState {
+    pub async fn new(
+        config_path: &Path,
+        config: ServerConfig,
+        checksum_cache: Arc<ChecksumCache>,
+        cert_store: Option<Arc<CertStore>>,
+    ) -> Result<Self> {
+        let metadata = match load_metadata(&config).await {
+            Ok(metadata) => {
+                checksum_cache
+                    .seed_from_history(Path::new(&config.paths.kernels_dir), &metadata.history)
+                    .await;
+                Some(Arc::new(metadata))
+            }
+            Err(e) => {
+                error!("Could not load metadata: {}", e);
+                None
+            }
+        };
+        let snapshot = Snapshot {
+            public_key: load_signing_key(&config).await?,
+            registry: Arc::new(DeviceRegistry::new(&config.paths.metadata_dir)),
+            config,
+            metadata,
+        };
+
+        let (budget_checks, budget_checks_rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            config_path: config_path.to_path_buf(),
+            current: RwLock::new(Arc::new(snapshot)),
+            checksum_cache,
+            cert_store,
+            watchers: Mutex::new(Vec::new()),
+            listeners: tokio::sync::Mutex::new(Vec::new()),
+            stamps: Mutex::new(BTreeMap::new()),
+            budget_checks,
+            budget_checks_rx: Mutex::new(Some(budget_checks_rx)),
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn checksum_cache(&self) -> Arc<ChecksumCache> {
+        self.checksum_cache.clone()
+    }
+
+    // Serve the configured listeners: start the ones not running yet and stop
+    // the ones no longer configured. New sockets are bound before any are
+    // closed, except those that need a port a closing listener still holds.
+    // Closed listeners stop accepting, but finish the downloads in progress.
+    pub async fn apply_listeners(
+        self: &Arc<Self>,
+        config: &ServerConfig,
+    ) -> Result<Vec<StartedListener>> {
+        let mut running = self.listeners.lock().await;
+        let wanted: Vec<(Listener, bool)> = config
+            .listeners()
+            .into_iter()
+            .map(|listener| {
+                let tls = self.cert_store.is_some() && config.serves_tls(&listener);
+                (listener, tls)
+            })
+            .collect();
+        let is_wanted = |r: &RunningListener| {
+            wanted
+                .iter()
+                .any(|(listener, tls)| r.listener == *listener && r.tls == *tls)
+        };
+
+        let mut to_start = Vec::new();
+        for (listener, tls) in &wanted {
+            if running
+                .iter()
+                .any(|r| r.listener == *listener && r.tls == *tls)
+            {
+                continue;
+            }
+            for addr in listener::resolve(&listener.host, listener.port).await? {
+                to_start.push((listener.clone(), *tls, addr));
+            }
+        }
+        let closing_ports: Vec<u16> = running
+            .iter()
+            .filter(|r| !is_wanted(r))
+            .map(|r| r.addr.port())
+            .collect();
+
+        // A socket that fails to bind here rejects the change, with nothing stopped
+        let mut bound = Vec::new();
+        let mut deferred = Vec::new();
+        for (listener, tls, addr) in to_start {
+            if closing_ports.contains(&addr.port()) {
+                deferred.push((listener, tls, addr));
+            } else {
+                bound.push((listener, tls, addr, listener::bind(addr)?));
+            }
+        }
+
+        let (kept, closing): (Vec<_>, Vec<_>) = running.drain(..).partition(|r| is_wanted(r));
+        *running = kept;
+        let mut stopped = Vec::new();
+        for r in closing {
+            stopped.push((r.listener.clone(), r.tls, r.addr));
+            r.stop().await;
+        }
+        let mut result = Ok(());
+        for (listener, tls, addr) in deferred {
+            match listener::bind(addr) {
+                Ok(socket) => bound.push((listener, tls, addr, socket)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // The change is rejected, so go back to the listeners of the old config
+        if result.is_err() {
+            bound.clear();
+            for (listener, tls, addr) in stopped {
+                match listener::bind(addr) {
+                    Ok(socket) => bound.push((listener, tls, addr, socket)),
+                    Err(e) => error!("Cannot restore listener {}: {}", addr, e),
+                }
+            }
+        }
+
+        let mut started = Vec::new();
+        for (listener, tls, addr, socket) in bound {
+            let routes = handlers::routes(listener.routes.clone(), Arc::clone(self));
+            let cert_store = self.cert_store.clone().filter(|_| tls);
+            let (stop, stopped) = oneshot::channel();
+            let task = tokio::spawn(listener::serve(socket, cert_store, routes, stopped));
+            started.push(StartedListener {
+                addr,
+                tls,
+                routes: listener.routes.clone(),
+            });
+            running.push(RunningListener {
+                listener,
+                tls,
+                addr,
+                stop,
+                task,
+            });
+        }
+        result.map(|()| started)
+    }
+
+    // Queue a check of a release's failure budget
+    pub fn check_failure_budget(&self, version: &str) {
+        let _ = self.budget_checks.send(version.to_string());
+    }
+
+    // Pause releases once too many devices fail to install them. Devices are
+    // then offered the previous good release until the rollout is resumed.
+    // Runs until the server exits.
+    pub fn spawn_failure_budget_monitor(self: &Arc<Self>) {
+        let Some(mut rx) = self.budget_checks_rx.lock().unwrap().take() else {
+            return;
+        };
+        let state = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                // A burst of failures for one release is checked once
+                let mut pending = BTreeSet::from([first]);
+                while let Ok(next) = rx.try_recv() {
+                    pending.insert(next);
+                }
+                let snapshot = state.snapshot();
+                for version in pending {
+                    if let Err(e) = enforce_failure_budget(&snapshot, &version).await {
+                        warn!("Failed to evaluate failure budget of {}: {}", version, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Watch server.toml, the metadata, kernels_dir and the TLS certificate,
+    // and reload on SIGHUP.
+    // Runs until the server exits.
+    pub fn spawn_reloader(self: &Arc<Self>) -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        self.watch(&self.snapshot().config, &tx)?;
+
+        #[cfg(unix)]
+        {
+            let tx = tx.clone();
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    info!("SIGHUP received, reloading configuration");
+                    let _ = tx.send(Reload::Config);
+                }
+            });
+        }
+
+        // Fallback for changes the watches missed
+        {
+            let tx = tx.clone();
+            let state = Arc::clone(self);
+            tokio::spawn(async move {
+                *state.stamps.lock().unwrap() = state.file_stamps().await;
+                let mut interval = tokio::time::interval(RECHECK_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let current = state.file_stamps().await;
+                    let handled = state.stamps.lock().unwrap().clone();
+                    for (reload, stamp) in current {
+                        if handled.get(&reload) != Some(&stamp) {
+                            warn!("Missed a change to the {:?} files, reloading", reload);
+                            let _ = tx.send(reload);
+                        }
+                    }
+                }
+            });
+        }
+
+        let state = Arc::clone(self);
+        tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                let mut pending = BTreeSet::from([first]);
+                while let Ok(next) = rx.try_recv() {
+                    pending.insert(next);
+                }
+                // A config reload also reloads everything else
+                if pending.contains(&Reload::Config) {
+                    pending = BTreeSet::from([Reload::Config]);
+                }
+                // Taken before reloading, so a change made meanwhile is seen next time
+                *state.stamps.lock().unwrap() = state.file_stamps().await;
+
+                for reload in pending {
+                    let result = match reload {
+                        Reload::Config => state.reload_config(&tx).await,
+                        Reload::Certificates => state.reload_certificates(),
+                        Reload::Metadata => state.reload_metadata().await,
+                    };
+                    if let Err(e) = result {
+                        error!("Reload rejected, keeping the current state: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn reload_config(self: &Arc<Self>, tx: &mpsc::UnboundedSender<Reload>) -> Result<()> {
+        let config = ServerConfig::load_from_file(&self.config_path)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}: {}", self.config_path.display(), e))?;
+        config.validate()?;
+        config.ensure_directories().await?;
+        let public_key = load_signing_key(&config).await?;
+        let metadata = load_metadata(&config).await?;
+
+        let old = self.snapshot();
+        match (&self.cert_store, &config.tls) {
+            (Some(cert_store), Some(tls)) => cert_store.reload(tls)?,
+            (None, None) => {}
+            _ => warn!("Enabling or disabling TLS takes effect after a restart"),
+        }
+        let changes = config_diff(&old.config, &config);
+        for change in &changes {
+            info!("Config changed: {}", change);
+        }
+        if old.config.listeners() != config.listeners() {
+            let advertised = |config: &ServerConfig| {
+                config
+                    .listeners()
+                    .into_iter()
+                    .find(|listener| listener.routes.contains(&RouteGroup::Device))
+            };
+            if advertised(&old.config) != advertised(&config) {
+                warn!("mDNS advertises the new device listener after a restart");
+            }
+            for started in self.apply_listeners(&config).await? {
+                info!(
+                    "Listening on {}://{} ({} routes)",
+                    if started.tls { "https" } else { "http" },
+                    started.addr,
+                    route_names(&started.routes)
+                );
+            }
+        }
+
+        let paths_changed = serde_json::to_value(&old.config.paths)?
+            != serde_json::to_value(&config.paths)?
+            || serde_json::to_value(&old.config.storage)? != serde_json::to_value(&config.storage)?
+            || old.config.tls != config.tls;
+        if paths_changed {
+            self.checksum_cache
+                .seed_from_history(Path::new(&config.paths.kernels_dir), &metadata.history)
+                .await;
+            self.watch(&config, tx)?;
+        }
+
+        let registry = if old.config.paths.metadata_dir == config.paths.metadata_dir {
+            old.registry.clone()
+        } else {
+            Arc::new(DeviceRegistry::new(&config.paths.metadata_dir))
+        };
+        log_metadata_changes(old.metadata.as_deref(), &metadata);
+
+        *self.current.write().unwrap() = Arc::new(Snapshot {
+            config,
+            public_key,
+            registry,
+            metadata: Some(Arc::new(metadata)),
+        });
+        if changes.is_empty() {
+            info!("Configuration reloaded, no changes");
+        }
+        Ok(())
+    }
+
+    fn reload_certificates(&self) -> Result<()> {
+        let snapshot = self.snapshot();
+        match (&self.cert_store, &snapshot.config.tls) {
+            (Some(cert_store), Some(tls)) => cert_store.reload(tls),
+            _ => Ok(()),
+        }
+    }
+
+    async fn reload_metadata(&self) -> Result<()> {
+        let old = self.snapshot();
+        let metadata = load_metadata(&old.config).await?;
+        if !log_metadata_changes(old.metadata.as_deref(), &metadata) {
+            return Ok(());
+        }
+
+        *self.current.write().unwrap() = Arc::new(Snapshot {
+            config: old.config.clone(),
+            public_key: old.public_key,
+            registry: old.registry.clone(),
+            metadata: Some(Arc::new(metadata)),
+        });
+        Ok(())
+    }
+
+    // Modification time and size of the files behind each kind of reload
+    async fn file_stamps(&self) -> BTreeMap<Reload, Vec<FileStamp>> {
+        let config = self.snapshot().config.clone();
+        let mut stamps = BTreeMap::new();
+        stamps.insert(
+            Reload::Config,
+            file_stamp(&self.config_path).await.into_iter().collect(),
+        );
+        let mut metadata = dir_stamps(Path::new(&config.paths.metadata_dir)).await;
+        if config.storage.backend == StoreBackend::Sqlite
+            && let Some(sqlite_path) = &config.storage.sqlite_path
+        {
+            metadata.extend(file_stamp(Path::new(sqlite_path)).await);
+        }
+        stamps.insert(Reload::Metadata, metadata);
+        stamps
+    }
+
+    // Replace all watches with ones for the given configuration
+    fn watch(&self, config: &ServerConfig, tx: &mpsc::UnboundedSender<Reload>) -> Result<()> {
+        let mut watchers = vec![
+            self.checksum_cache
+                .watch(Path::new(&config.paths.kernels_dir))?,
+        ];
+
+        // Editors often replace the file, so watch its directory rather than the file
+        let config_name = self.config_path.file_name().map(|name| name.to_os_string());
+        let config_dir = parent_dir(&self.config_path);
+        if config_dir.is_dir() {
+            watchers.push(watch_dir(&config_dir, tx.clone(), move |path| {
+                (path.file_name().map(|name| name.to_os_string()) == config_name)
+                    .then_some(Reload::Config)
+            })?);
+        } else {
+            warn!(
+                "Not watching {}: directory does not exist",
+                self.config_path.display()
+            );
+        }
+
+        // Only the metadata files themselves; not the lock file, temp files
+        // or the device registry in its own subdirectory
+        let mut metadata_dirs = vec![PathBuf::from(&config.paths.metadata_dir)];
+        if config.storage.backend == StoreBackend::Sqlite
+            && let Some(sqlite_path) = &config.storage.sqlite_path
+        {
+            let sqlite_dir = parent_dir(Path::new(sqlite_path));
+            if !metadata_dirs.contains(&sqlite_dir) {
+                metadata_dirs.push(sqlite_dir);
+            }
+        }
+        for dir in metadata_dirs {
+            watchers.push(watch_dir(&dir, tx.clone(), |path| {
+                let name = path.file_name()?.to_string_lossy();
+                (!name.starts_with('.')).then_some(Reload::Metadata)
+            })?);
+        }
+
+        // Renewals usually replace the certificate and key within moments of
+        // each other; the debounce turns that into a single reload
+        if self.cert_store.is_some()
+            && let Some(tls) = &config.tls
+        {
+            let mut files: Vec<PathBuf> = vec![PathBuf::from(&tls.cert), PathBuf::from(&tls.key)];
+            files.extend(tls.ca_chain.as_ref().map(PathBuf::from));
+            // A new device CA or revocation list applies to the next handshake
+            if let Some(client_auth) = &tls.client_auth {
+                files.push(PathBuf::from(&client_auth.ca));
+                files.extend(client_auth.crl.as_ref().map(PathBuf::from));
+            }
+            let mut dirs: Vec<PathBuf> = files.iter().map(|file| parent_dir(file)).collect();
+            dirs.sort();
+            dirs.dedup();
+            let names: Vec<_> = files
+                .iter()
+                .filter_map(|file| file.file_name().map(|name| name.to_os_string()))
+                .collect();
+            for dir in dirs {
+                let names = names.clone();
+                watchers.push(watch_dir(&dir, tx.clone(), move |path| {
+                    let name = path.file_name()?;
+                    names
+                        .iter()
+                        .any(|n| n == name)
+                        .then_some(Reload::Certificates)
+                })?);
+            }
+        }
+
+        *self.watchers.lock().unwrap() = watchers;
+        Ok(())
+    }
+}
+
+async fn enforce_failure_budget(snapshot: &Snapshot, version: &str) -> Result<()> {
+    let manager = MetadataManager::new(&snapshot.config);
+    let rollouts = manager.load_rollouts().await?;
+    let now = chrono::Utc::now();
+    let pause = snapshot
+        .registry
+        .with_events(|events| rollouts.evaluate_failure_budget(version, events, now))
+        .await?;
+    let Some(pause) = pause else {
+        return Ok(());
+    };
+
+    warn!("Pausing rollout of {}: {}", version, pause.reason);
+    manager.pause_rollout(version, pause).await
+}
+
+type FileStamp = (PathBuf, Option<SystemTime>, u64);
+
+async fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((path.to_path_buf(), metadata.modified().ok(), metadata.len()))
+}
+
+// The files directly in a directory, skipping hidden ones such as the lock file
+async fn dir_stamps(dir: &Path) -> Vec<FileStamp> {
+    let mut stamps = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return stamps;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if let Some(stamp) = file_stamp(&entry.path()).await
+            && entry
+                .file_type()
+                .await
+                .is_ok_and(|file_type| file_type.is_file())
+        {
+            stamps.push(stamp);
+        }
+    }
+    stamps.sort();
+    stamps
+}
+
+pub fn route_names(routes: &[RouteGroup]) -> String {
+    let names: Vec<String> = routes.iter().map(|group| group.to_string()).collect();
+    names.join(", ")
+}
+
+fn watch_dir<F>(
+    dir: &Path,
+    tx: mpsc::UnboundedSender<Reload>,
+    classify: F,
+) -> Result<RecommendedWatcher>
+where
+    F: Fn(&Path) -> Option<Reload> + Send + 'static,
+{
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                for reload in event.paths.iter().filter_map(|path| classify(path)) {
+                    let _ = tx.send(reload);
+                }
+            }
+            Err(e) => warn!("Watch error: {}", e),
+        })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+async fn load_metadata(config: &ServerConfig) -> Result<MetadataSnapshot> {
+    let store = open_store(config);
+    Ok(MetadataSnapshot {
+        history: store.load_history().await?,
+        rollouts: store.load_rollouts().await?,
+    })
+}
+
+async fn load_signing_key(config: &ServerConfig) -> Result<Option<VerifyingKey>> {
+    match &config.signing {
+        Some(signing) => Ok(Some(load_public_key(&signing.public_key).await?)),
+        None => Ok(None),
+    }
+}
+
+// Log what a metadata reload changes. Returns false if nothing did.
+fn log_metadata_changes(old: Option<&MetadataSnapshot>, new: &MetadataSnapshot) -> bool {
+    let Some(old) = old else {
+        info!("Metadata loaded: {} releases", new.history.versions.len());
+        return true;
+    };
+
+    let changes = metadata_diff(old, new);
+    for change in &changes {
+        info!("Metadata changed: {}", change);
+    }
+    !changes.is_empty()
+}
+
+fn metadata_diff(old: &MetadataSnapshot, new: &MetadataSnapshot) -> Vec<String> {
+    let releases = |history: &VersionHistory| -> BTreeMap<String, String> {
+        history
+            .versions
+            .iter()
+            .map(|kernel| {
+                let json = serde_json::to_string(kernel).unwrap_or_default();
+                (kernel.version.clone(), json)
+            })
+            .collect()
+    };
+    let rollouts = |state: &RolloutState| -> BTreeMap<String, String> {
+        state
+            .releases
+            .iter()
+            .map(|(version, rollout)| {
+                let json = serde_json::to_string(rollout).unwrap_or_default();
+                (version.clone(), json)
+            })
+            .collect()
+    };
+
+    let mut changes = Vec::new();
+    diff_maps(
+        &releases(&old.history),
+        &releases(&new.history),
+        |version, change| changes.push(format!("release {} {}", version, change.describe())),
+    );
+    diff_maps(
+        &old.history.channels,
+        &new.history.channels,
+        |channel, change| changes.push(format!("channel {} head {}", channel, change.show())),
+    );
+    diff_maps(
+        &old.history.pinned,
+        &new.history.pinned,
+        |channel, change| changes.push(format!("channel {} pin {}", channel, change.show())),
+    );
+    diff_maps(
+        &rollouts(&old.rollouts),
+        &rollouts(&new.rollouts),
+        |version, change| changes.push(format!("rollout of {} {}", version, change.describe())),
+    );
+    changes
+}
+
+// Changed settings, one "key: old -> new" line each, with nested keys
+// flattened (e.g. "server.port: 8080 -> 8081")
+pub fn config_diff(old: &ServerConfig, new: &ServerConfig) -> Vec<String> {
+    let flatten = |config: &ServerConfig| {
+        let mut settings = BTreeMap::new();
+        if let Ok(value) = serde_json::to_value(config) {
+            flatten_value("", &value, &mut settings);
+        }
+        settings
+    };
+
+    let mut changes = Vec::new();
+    diff_maps(&flatten(old), &flatten(new), |key, change| {
+        changes.push(format!("{}: {}", key, change.show()))
+    });
+    changes
+}
+
+fn flatten_value(prefix: &str, value: &serde_json::Value, out: &mut BTreeMap<String, String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_value(&key, value, out);
+            }
+        }
+        serde_json::Value::Null => {}
+        value => {
+            out.insert(prefix.to_string(), value.to_string());
+        }
+    }
+}
+
+enum Change<'a> {
+    Added(&'a str),
+    Removed(&'a str),
+    Modified(&'a str, &'a str),
+}
+
+impl Change<'_> {
+    fn show(&self) -> String {
+        match self {
+            Change::Added(new) => format!("(unset) -> {}", new),
+            Change::Removed(old) => format!("{} -> (unset)", old),
+            Change::Modified(old, new) => format!("{} -> {}", old, new),
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Change::Added(_) => "added",
+            Change::Removed(_) => "removed",
+            Change::Modified(..) => "updated",
+        }
+    }
+}
+
+fn diff_maps<'a, F>(old: &'a BTreeMap<String, String>, new: &'a BTreeMap<String, String>, mut f: F)
+where
+    F: FnMut(&'a str, Change<'a>),
+{
+    for (key, old_value) in old {
+        match new.get(key) {
+            None => f(key, Change::Removed(old_value)),
+            Some(new_value) if new_value != old_value => {
+                f(key, Change::Modified(old_value, new_value))
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, new_value) in new {
+        if !old.contains_key(key) {
+            f(key, Change::Added(new_value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_diff() {
+        let old = ServerConfig::default();
+        let mut new = old.clone();
+        assert!(config_diff(&old, &new).is_empty());
+
+        new.server.port = 8081;
+        new.paths.kernels_dir = "/srv/kernels".to_string();
+        new.storage.sqlite_path = Some("/srv/metadata.db".to_string());
+        assert_eq!(
+            config_diff(&old, &new),
+            vec![
+                r#"paths.kernels_dir: "./kernels" -> "/srv/kernels""#,
+                "server.port: 8080 -> 8081",
+                r#"storage.sqlite_path: (unset) -> "/srv/metadata.db""#,
+            ]
+        );
+    }
+}
@@ -1,20 +1,52 @@
+mod admin;
+mod atomic_file;
+mod blob_store;
 mod checksum;
+mod checksum_cache;
 mod cli;
+mod compatibility;
 mod config;
+mod delta;
+mod device_registry;
 mod handlers;
+mod listener;
+mod live_state;
 mod mdns;
 mod metadata;
 mod metadata_manager;
+mod metadata_store;
+mod range;
+mod repair;
+mod rollout;
+mod safe_path;
+mod signing;
+mod sqlite_store;
+mod tls;
+mod update_check;
+mod upload;
+mod versioning;
 
 use anyhow::Result;
+use checksum_cache::ChecksumCache;
 use clap::Parser;
 use cli::{Cli, Commands};
-use config::ServerConfig;
-use handlers::{health, kernels, version};
+use compatibility::Compatibility;
+use config::{RouteGroup, ServerConfig, TokenScope};
+use device_registry::DeviceRegistry;
+use live_state::{LiveState, route_names};
 use mdns::MdnsServiceWrapper;
-use metadata_manager::MetadataManager;
+use metadata::{DEFAULT_CHANNEL, ReleaseDetails, ReleaseStatus};
+use metadata_manager::{MetadataManager, ReleaseOptions};
+use metadata_store::StoreBackend;
+use repair::RecoverySource;
+use rollout::FailureBudget;
+use signing::{ReleaseSigner, key_fingerprint, public_key_base64, verify_release};
+use std::path::Path;
+use std::sync::Arc;
+use tls::CertStore;
+use tokio_util::io::ReaderStream;
 use tracing_subscriber::fmt::init;
-use warp::Filter;
+use upload::{render_file_name, store_stream};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -29,68 +61,395 @@ async fn main() -> Result<()> {
         Commands::AddKernel {
             version,
             file,
+            from,
+            sha256,
             description,
+            channel,
+            force_latest,
+            boards,
+            min_bootloader,
+            requires_from,
+            rollout,
+            max_failure_percent,
+            failure_window,
+            min_attempts,
+            deltas,
+            import,
+            release_notes,
+            cves,
+            severity,
+            git_commit,
+            build_id,
+            labels,
+            details,
             config,
         } => {
-            add_kernel_command(config, version, file, description).await?;
+            // Any budget flag overrides the default budget; unset fields keep their defaults
+            let failure_budget = (max_failure_percent.is_some()
+                || failure_window.is_some()
+                || min_attempts.is_some())
+            .then(|| {
+                let default = FailureBudget::default();
+                FailureBudget {
+                    max_failure_percent: max_failure_percent.unwrap_or(default.max_failure_percent),
+                    window_minutes: failure_window.unwrap_or(default.window_minutes),
+                    min_attempts: min_attempts.unwrap_or(default.min_attempts),
+                }
+            });
+            // Flags take precedence over the sidecar file
+            let mut release_details = match details {
+                Some(path) => read_release_details(&path).await?,
+                None => ReleaseDetails::default(),
+            };
+            let release_notes =
+                match release_notes {
+                    Some(path) => Some(tokio::fs::read_to_string(&path).await.map_err(|e| {
+                        anyhow::anyhow!("Cannot read release notes {}: {}", path, e)
+                    })?),
+                    None => None,
+                };
+            release_details.merge(ReleaseDetails {
+                release_notes,
+                cves,
+                severity,
+                git_commit,
+                build_id,
+                labels: labels.into_iter().collect(),
+            });
+            let options = ReleaseOptions {
+                channel,
+                force_latest,
+                compatibility: Some(Compatibility {
+                    boards,
+                    min_bootloader,
+                    requires_from,
+                }),
+                rollout,
+                failure_budget,
+                delta_predecessors: deltas,
+                import,
+                details: release_details,
+            };
+            let source = match from {
+                Some(path) => ImageSource::Stream {
+                    path,
+                    file_name_template: file
+                        .unwrap_or_else(|| DEFAULT_FILE_NAME_TEMPLATE.to_string()),
+                    expected_checksum: sha256,
+                },
+                None => ImageSource::KernelsDir(file.unwrap_or_default()),
+            };
+            add_kernel_command(config, version, source, description, options).await?;
         }
         Commands::List { config } => {
             list_kernels_command(config).await?;
         }
+        Commands::Promote {
+            version,
+            channel,
+            force_latest,
+            config,
+        } => {
+            promote_command(config, version, channel, force_latest).await?;
+        }
+        Commands::Rollout {
+            version,
+            percent,
+            config,
+        } => {
+            rollout_command(config, version, percent).await?;
+        }
+        Commands::Resume { version, config } => {
+            resume_command(config, version).await?;
+        }
+        Commands::Yank {
+            version,
+            force,
+            config,
+        } => {
+            set_status_command(config, version, ReleaseStatus::Yanked, force).await?;
+        }
+        Commands::Deprecate { version, config } => {
+            set_status_command(config, version, ReleaseStatus::Deprecated, false).await?;
+        }
+        Commands::Remove {
+            version,
+            delete_image,
+            force,
+            config,
+        } => {
+            remove_command(config, version, delete_image, force).await?;
+        }
+        Commands::Rollback {
+            to,
+            channel,
+            reason,
+            by,
+            yank,
+            config,
+        } => {
+            rollback_command(config, to, channel, reason, by, yank).await?;
+        }
+        Commands::Repair { write, config } => {
+            repair_command(config, write).await?;
+        }
+        Commands::MigrateStore { from, to, config } => {
+            migrate_store_command(config, from, to).await?;
+        }
+        Commands::Gc { dry_run, config } => {
+            gc_command(config, dry_run).await?;
+        }
+        Commands::Devices {
+            device,
+            stats,
+            config,
+        } => {
+            devices_command(config, device, stats).await?;
+        }
+        Commands::Keygen { out } => {
+            keygen_command(out).await?;
+        }
+        Commands::GenSelfSigned { out_dir, hostnames } => {
+            gen_self_signed_command(out_dir, hostnames).await?;
+        }
+        Commands::HashToken { name, scopes } => {
+            hash_token_command(name, scopes);
+        }
     }
 
     Ok(())
 }
 
 async fn start_server(config_path: String) -> Result<()> {
-    let config = ServerConfig::load_from_file(&config_path)
-        .await
-        .unwrap_or_else(|_| {
+    // Only a missing file means defaults; a broken one must not start the
+    // server without its TLS settings and tokens
+    let config = match ServerConfig::load_from_file(&config_path).await {
+        Ok(config) => config,
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
             println!("Using default configuration");
             ServerConfig::default()
-        });
+        }
+        Err(e) => return Err(anyhow::anyhow!("{}: {}", config_path, e)),
+    };
 
+    config.validate()?;
     config.ensure_directories().await?;
 
-    let addr = ([0, 0, 0, 0], config.server.port);
-
-    let routes = health()
-        .or(version(config.clone()))
-        .or(kernels(config.clone()));
+    let cert_store = match &config.tls {
+        Some(tls) => Some(Arc::new(CertStore::load(tls)?)),
+        None => None,
+    };
 
-    println!(
-        "OTA Server running on http://{}:{}",
-        config.server.host, config.server.port
+    // server.toml, the metadata and the certificate are reloaded in place when they change
+    let state = Arc::new(
+        LiveState::new(
+            Path::new(&config_path),
+            config.clone(),
+            Arc::new(ChecksumCache::new()),
+            cert_store.clone(),
+        )
+        .await?,
     );
+    state.spawn_reloader()?;
+    state.spawn_failure_budget_monitor();
+
+    // Bind everything up front, so a bad address stops the server at startup
+    for started in state.apply_listeners(&config).await? {
+        println!(
+            "OTA Server running on {}://{} ({} routes)",
+            if started.tls { "https" } else { "http" },
+            started.addr,
+            route_names(&started.routes)
+        );
+    }
     println!("Kernels directory: {}", config.paths.kernels_dir);
     println!("Metadata directory: {}", config.paths.metadata_dir);
 
-    // Start mDNS service advertisement
-    let mut mdns_service = MdnsServiceWrapper::new(config.server.port, &config.server.host)?;
-    mdns_service.start().await?;
+    // Advertise the first listener devices can use
+    let device_listener = config
+        .listeners()
+        .into_iter()
+        .find(|listener| listener.routes.contains(&RouteGroup::Device));
+    let _mdns_service = match device_listener {
+        Some(listener) => {
+            // Clients use the scheme to decide whether to connect with TLS
+            let scheme = if config.serves_tls(&listener) {
+                "https"
+            } else {
+                "http"
+            };
+            let mut mdns_service =
+                MdnsServiceWrapper::new(listener.port, &listener.host)?.with_txt("scheme", scheme);
+            mdns_service.start().await?;
+            println!("mDNS service started - advertising as _ota._tcp.local");
+            Some(mdns_service)
+        }
+        None => None,
+    };
 
-    println!("mDNS service started - advertising as _ota._tcp.local");
+    // Listeners are served in the background and rebound on config changes
+    std::future::pending::<()>().await;
+    Ok(())
+}
 
-    warp::serve(routes).run(addr).await;
+// Sidecar file with the release notes and build details of an image
+async fn read_release_details(path: &str) -> Result<ReleaseDetails> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path, e))?;
+    toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid release details in {}: {}", path, e))
+}
 
-    Ok(())
+// Where add-kernel takes the image from
+enum ImageSource {
+    // A file already in kernels_dir (or, with --import, any path)
+    KernelsDir(String),
+    // A path or "-" for stdin, copied into kernels_dir under a templated name
+    Stream {
+        path: String,
+        file_name_template: String,
+        expected_checksum: Option<String>,
+    },
 }
 
+const DEFAULT_FILE_NAME_TEMPLATE: &str = "kernel-v{version}.img";
+
 async fn add_kernel_command(
     config_path: String,
     version: String,
-    file: String,
+    source: ImageSource,
     description: String,
+    options: ReleaseOptions,
 ) -> Result<()> {
     let config = ServerConfig::load_from_file(&config_path).await?;
     config.ensure_directories().await?;
 
-    let manager = MetadataManager::new(config.paths.kernels_dir, config.paths.metadata_dir);
+    // Refuse to publish releases devices would fail to verify
+    let signing_keys = match &config.signing {
+        Some(signing) => Some(ReleaseSigner::load_configured(signing).await?),
+        None => None,
+    };
 
-    manager
-        .add_kernel(version.clone(), file, description)
-        .await?;
-    println!("Successfully added kernel version: {}", version);
+    let manager = MetadataManager::new(&config);
+
+    // Before anything is copied into kernels_dir
+    options.details.validate().map_err(anyhow::Error::msg)?;
+    let (file, copied) = match source {
+        ImageSource::KernelsDir(file) => (file, false),
+        ImageSource::Stream {
+            path,
+            file_name_template,
+            expected_checksum,
+        } => {
+            let file_name = render_file_name(&file_name_template, &version, &options.channel)?;
+            let kernels_dir = Path::new(&config.paths.kernels_dir);
+            let stored = if path == "-" {
+                let stream = ReaderStream::new(tokio::io::stdin());
+                store_stream(
+                    kernels_dir,
+                    &file_name,
+                    stream,
+                    u64::MAX,
+                    expected_checksum.as_deref(),
+                )
+                .await?
+            } else {
+                let file = tokio::fs::File::open(&path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Cannot open {}: {}", path, e))?;
+                let stream = ReaderStream::new(file);
+                store_stream(
+                    kernels_dir,
+                    &file_name,
+                    stream,
+                    u64::MAX,
+                    expected_checksum.as_deref(),
+                )
+                .await?
+            };
+            if stored.created {
+                println!(
+                    "Copied {} bytes to {} ({})",
+                    stored.file_size, stored.file_name, stored.checksum
+                );
+            } else {
+                println!("{} already holds this image", stored.file_name);
+            }
+            (stored.file_name, stored.created)
+        }
+    };
+
+    let kernel_info = match manager
+        .add_kernel(
+            version.clone(),
+            file.clone(),
+            description,
+            options.clone(),
+            signing_keys.as_ref().map(|(signer, _)| signer),
+        )
+        .await
+    {
+        Ok(kernel_info) => kernel_info,
+        Err(e) => {
+            // Don't leave behind an image copied only for this release, unless
+            // a release published meanwhile already uses it
+            if copied
+                && let Ok(history) = manager.list_versions().await
+                && !history.versions.iter().any(|v| v.kernel_file == file)
+                && tokio::fs::remove_file(Path::new(&config.paths.kernels_dir).join(&file))
+                    .await
+                    .is_ok()
+            {
+                println!("Removed {}", file);
+            }
+            return Err(e);
+        }
+    };
+
+    if let Some((_, public_key)) = &signing_keys {
+        verify_release(&kernel_info, public_key)?;
+        println!("Signed with key: {}", key_fingerprint(public_key));
+    }
+    for delta in &kernel_info.deltas {
+        println!(
+            "Built delta from {}: {} bytes ({} bytes full image)",
+            delta.from_version, delta.file_size, kernel_info.file_size
+        );
+    }
+    println!(
+        "Successfully added kernel version: {} (channel: {})",
+        kernel_info.version, options.channel
+    );
+
+    Ok(())
+}
+
+async fn promote_command(
+    config_path: String,
+    version: String,
+    channel: String,
+    force_latest: bool,
+) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    manager.promote(&version, &channel, force_latest).await?;
+    println!("Promoted kernel version {} to channel {}", version, channel);
+
+    let history = manager.list_versions().await?;
+    if let Some(head) = history.channels.get(&channel)
+        && head != &version
+    {
+        println!(
+            "Channel {} stays on higher version {} (use --force-latest to override)",
+            channel, head
+        );
+    }
 
     Ok(())
 }
@@ -98,15 +457,24 @@ async fn add_kernel_command(
 async fn list_kernels_command(config_path: String) -> Result<()> {
     let config = ServerConfig::load_from_file(&config_path).await?;
 
-    let manager = MetadataManager::new(config.paths.kernels_dir, config.paths.metadata_dir);
+    let manager = MetadataManager::new(&config);
 
     let history = manager.list_versions().await?;
+    let rollouts = manager.load_rollouts().await?;
 
     println!("Available kernel versions:");
     println!("Latest: {}", history.latest);
+    for (channel, head) in &history.channels {
+        let pinned = if history.pinned.contains_key(channel) {
+            " (pinned)"
+        } else {
+            ""
+        };
+        println!("  {}: {}{}", channel, head, pinned);
+    }
     println!();
 
-    for kernel in &history.versions {
+    for kernel in history.sorted_versions() {
         println!("Version: {}", kernel.version);
         println!("  File: {}", kernel.kernel_file);
         println!("  Size: {} bytes", kernel.file_size);
@@ -115,8 +483,451 @@ async fn list_kernels_command(config_path: String) -> Result<()> {
             kernel.release_date.format("%Y-%m-%d %H:%M:%S UTC")
         );
         println!("  Description: {}", kernel.description);
+        println!("  Channels: {}", kernel.channels.join(", "));
+        print_release_details(&kernel.details);
+        if !kernel.status.is_active() {
+            println!("  Status: {}", kernel.status);
+        }
+        for rollback in &kernel.rollbacks {
+            println!(
+                "  Rolled back on {} to {} by {} at {}: {}",
+                rollback.channel,
+                rollback.to_version,
+                rollback.by,
+                rollback.at.format("%Y-%m-%d %H:%M:%S UTC"),
+                rollback.reason
+            );
+        }
+        println!("  Rollout: {}%", rollouts.percentage(&kernel.version));
+        if let Some(pause) = rollouts.paused(&kernel.version) {
+            println!(
+                "  PAUSED since {}: {}",
+                pause.paused_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                pause.reason
+            );
+        }
+        if let Some(compatibility) = &kernel.compatibility {
+            if !compatibility.boards.is_empty() {
+                println!("  Boards: {}", compatibility.boards.join(", "));
+            }
+            if let Some(min_bootloader) = &compatibility.min_bootloader {
+                println!("  Min bootloader: {}", min_bootloader);
+            }
+            if let Some(requires_from) = &compatibility.requires_from {
+                println!("  Upgrade from: {}", requires_from);
+            }
+        }
+        if let Some(signature) = &kernel.signature {
+            println!("  Signed by: {}", signature.key_fingerprint);
+        }
         println!();
     }
 
     Ok(())
 }
+
+async fn rollout_command(config_path: String, version: String, percent: u8) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    manager.set_rollout(&version, percent).await?;
+    println!(
+        "Kernel version {} is now rolled out to {}% of devices",
+        version, percent
+    );
+
+    Ok(())
+}
+
+async fn resume_command(config_path: String, version: String) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    manager.resume_rollout(&version).await?;
+    println!(
+        "Rollout of kernel version {} resumed at {}%",
+        version,
+        manager.load_rollouts().await?.percentage(&version)
+    );
+
+    Ok(())
+}
+
+async fn set_status_command(
+    config_path: String,
+    version: String,
+    status: ReleaseStatus,
+    force: bool,
+) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    manager.set_status(&version, status, force).await?;
+    println!("Kernel version {} is now {}", version, status);
+    print_latest(&manager).await
+}
+
+async fn remove_command(
+    config_path: String,
+    version: String,
+    delete_image: bool,
+    force: bool,
+) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    let (kernel_info, image_deleted) = manager
+        .remove_release(&version, delete_image, force)
+        .await?;
+    println!("Removed kernel version {}", version);
+    if image_deleted {
+        println!("Deleted image {}", kernel_info.kernel_file);
+    } else if delete_image {
+        println!(
+            "Kept image {}, another release uses it",
+            kernel_info.kernel_file
+        );
+    }
+    print_latest(&manager).await
+}
+
+async fn rollback_command(
+    config_path: String,
+    to_version: String,
+    channel: Option<String>,
+    reason: String,
+    by: Option<String>,
+    yank: bool,
+) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    let by = by
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    let rolled_back = manager
+        .rollback(&to_version, channel.as_deref(), yank, &by, &reason)
+        .await?;
+    for (channel, from_version) in &rolled_back {
+        println!(
+            "Rolled back channel {} from {} to {}",
+            channel, from_version, to_version
+        );
+    }
+    if yank {
+        let mut yanked: Vec<_> = rolled_back.iter().map(|(_, version)| version).collect();
+        yanked.dedup();
+        for version in yanked {
+            println!("Kernel version {} is now yanked", version);
+        }
+    }
+    println!("Devices on the rolled back versions are told to downgrade");
+    print_latest(&manager).await
+}
+
+fn print_release_details(details: &ReleaseDetails) {
+    if let Some(severity) = details.severity.filter(|severity| !severity.is_normal()) {
+        println!("  Severity: {}", severity);
+    }
+    if !details.cves.is_empty() {
+        println!("  CVEs: {}", details.cves.join(", "));
+    }
+    if let Some(git_commit) = &details.git_commit {
+        println!("  Commit: {}", git_commit);
+    }
+    if let Some(build_id) = &details.build_id {
+        println!("  Build: {}", build_id);
+    }
+    for (key, value) in &details.labels {
+        println!("  Label: {}={}", key, value);
+    }
+    if let Some(release_notes) = &details.release_notes {
+        println!("  Release notes:");
+        for line in release_notes.lines() {
+            println!("    {}", line);
+        }
+    }
+}
+
+// Channel heads after a release changed status or was removed
+async fn print_latest(manager: &MetadataManager) -> Result<()> {
+    let history = manager.list_versions().await?;
+    println!("Latest version: {}", history.latest);
+    for (channel, head) in &history.channels {
+        if channel != DEFAULT_CHANNEL {
+            println!("Channel {}: {}", channel, head);
+        }
+    }
+    Ok(())
+}
+
+async fn repair_command(config_path: String, write: bool) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    let (plan, quarantined) = manager.repair(write).await?;
+    match &plan.corruption {
+        Some(corruption) => println!("{}", corruption),
+        None => println!("Version history parses cleanly"),
+    }
+    for warning in &plan.warnings {
+        println!("Warning: {}", warning);
+    }
+    println!();
+
+    println!("Rebuilt history:");
+    for kernel in plan.history.sorted_versions() {
+        let source = match plan.sources.get(&kernel.version) {
+            Some(RecoverySource::History) => "version-history.json",
+            Some(RecoverySource::LatestJson) => "latest.json",
+            Some(RecoverySource::KernelsDir) => "kernels_dir scan",
+            None => "unknown",
+        };
+        let channels = if kernel.channels.is_empty() {
+            "no channel".to_string()
+        } else {
+            kernel.channels.join(", ")
+        };
+        println!(
+            "  {}  {}  [{}]  (from {})",
+            kernel.version, kernel.kernel_file, channels, source
+        );
+    }
+    for (channel, head) in &plan.history.channels {
+        println!("  {} head: {}", channel, head);
+    }
+    println!();
+
+    if !write {
+        println!("Preview only; run again with --write to apply");
+        return Ok(());
+    }
+    if let Some(quarantined) = quarantined {
+        println!("Corrupt history moved to {}", quarantined.display());
+    }
+    println!(
+        "Wrote version history with {} releases",
+        plan.history.versions.len()
+    );
+
+    Ok(())
+}
+
+async fn migrate_store_command(
+    config_path: String,
+    from: StoreBackend,
+    to: StoreBackend,
+) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    let (releases, rollouts) = manager.migrate_store(&config, from, to).await?;
+    println!(
+        "Copied {} releases and {} rollout entries from {} to {}",
+        releases, rollouts, from, to
+    );
+    if config.storage.backend != to {
+        println!(
+            "Set `backend = \"{}\"` in the [storage] section of {} to use it",
+            to, config_path
+        );
+    }
+
+    Ok(())
+}
+
+async fn gc_command(config_path: String, dry_run: bool) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let manager = MetadataManager::new(&config);
+
+    let removed = manager.collect_garbage(dry_run).await?;
+    let freed: u64 = removed.iter().map(|blob| blob.size).sum();
+    for blob in &removed {
+        println!("  {} ({} bytes)", blob.digest, blob.size);
+    }
+    if dry_run {
+        println!(
+            "Would remove {} unreferenced blobs ({} bytes)",
+            removed.len(),
+            freed
+        );
+    } else {
+        println!(
+            "Removed {} unreferenced blobs ({} bytes)",
+            removed.len(),
+            freed
+        );
+    }
+
+    Ok(())
+}
+
+async fn devices_command(config_path: String, device: Option<String>, stats: bool) -> Result<()> {
+    let config = ServerConfig::load_from_file(&config_path).await?;
+
+    let registry = DeviceRegistry::new(&config.paths.metadata_dir);
+
+    if stats {
+        println!("Install outcomes per release:");
+        for (version, stats) in registry.release_stats().await? {
+            let success_rate = stats
+                .success_rate
+                .map_or("n/a".to_string(), |rate| format!("{:.1}%", rate * 100.0));
+            println!(
+                "  {}: {} booted ok, {} rolled back, {} failed, {} in progress (success rate: {})",
+                version,
+                stats.booted_ok,
+                stats.rolled_back,
+                stats.failed,
+                stats.in_progress,
+                success_rate
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(device_id) = device {
+        let events = registry.device_events(&device_id).await?;
+        if events.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No events recorded for device: {}",
+                device_id
+            ));
+        }
+        println!("Events for device {}:", device_id);
+        for event in events {
+            println!(
+                "  {}  {}  {:?}{}",
+                event.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+                event.version,
+                event.status,
+                event
+                    .error_code
+                    .map(|code| format!(" (error: {})", code))
+                    .unwrap_or_default()
+            );
+        }
+        return Ok(());
+    }
+
+    let devices = registry.devices().await?;
+    println!("Known devices: {}", devices.len());
+    for device in devices {
+        println!(
+            "  {}  {}  {:?}  last seen {}",
+            device.device_id,
+            device.version,
+            device.status,
+            device.last_seen.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+
+    Ok(())
+}
+
+async fn keygen_command(out: String) -> Result<()> {
+    let public_path = format!("{}.pub", out);
+    let signer = ReleaseSigner::generate();
+    signer.save(&out, &public_path).await?;
+
+    let public_key = signer.verifying_key();
+    println!("Generated Ed25519 signing key");
+    println!("  Private key: {}", out);
+    println!("  Public key:  {}", public_path);
+    println!("  Public key (base64): {}", public_key_base64(&public_key));
+    println!("  Fingerprint: {}", key_fingerprint(&public_key));
+    println!();
+    println!("Add this to your server configuration:");
+    println!();
+    println!("[signing]");
+    println!("private_key = \"{}\"", out);
+    println!("public_key = \"{}\"", public_path);
+
+    Ok(())
+}
+
+async fn gen_self_signed_command(out_dir: String, hostnames: Vec<String>) -> Result<()> {
+    let out_dir = Path::new(&out_dir);
+    let cert_path = out_dir.join("cert.pem");
+    let key_path = out_dir.join("key.pem");
+    let (cert, key) = tls::generate_self_signed(hostnames.clone())?;
+
+    tokio::fs::create_dir_all(out_dir).await?;
+    // Never overwrite an existing key or certificate, and never leave half a
+    // pair behind
+    let mut key_file = create_new(&key_path, 0o600).await?;
+    let mut cert_file = match create_new(&cert_path, 0o644).await {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&key_path).await;
+            return Err(e);
+        }
+    };
+    let written = async {
+        tokio::io::AsyncWriteExt::write_all(&mut key_file, key.as_bytes()).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut cert_file, cert.as_bytes()).await
+    }
+    .await;
+    if let Err(e) = written {
+        for path in [&key_path, &cert_path] {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        return Err(e.into());
+    }
+
+    println!(
+        "Generated self-signed certificate for {}",
+        hostnames.join(", ")
+    );
+    println!("  Certificate: {}", cert_path.display());
+    println!("  Private key: {}", key_path.display());
+    println!();
+    println!("Add this to your server configuration:");
+    println!();
+    println!("[tls]");
+    println!("cert = \"{}\"", cert_path.display());
+    println!("key = \"{}\"", key_path.display());
+
+    Ok(())
+}
+
+// Create a file that must not exist yet, with `mode` on unix
+async fn create_new(path: &Path, mode: u32) -> Result<tokio::fs::File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))
+}
+
+// The token itself is only shown here; server.toml only gets its hash
+fn hash_token_command(name: String, scopes: Vec<TokenScope>) {
+    let token = admin::generate_token();
+    let scopes: Vec<String> = scopes
+        .iter()
+        .map(|scope| format!("\"{}\"", scope))
+        .collect();
+
+    println!("Token: {}", token);
+    println!("Store it now; it cannot be recovered from the configuration.");
+    println!();
+    println!("Add this to your server configuration:");
+    println!();
+    println!("[[admin_tokens]]");
+    println!("name = {}", toml::Value::from(name));
+    println!("sha256 = \"{}\"", admin::hash_token(&token));
+    println!("scopes = [{}]", scopes.join(", "));
+}
@@ -13,13 +13,23 @@ pub struct ServiceContext {
 
 pub struct MdnsServiceWrapper {
     port: u16,
+    // Extra TXT record entries
+    txt: Vec<(String, String)>,
 }
 
 impl MdnsServiceWrapper {
     pub fn new(port: u16, _host: &str) -> Result<Self> {
         info!("Creating mDNS service for _ota._tcp.local on port {}", port);
 
-        Ok(Self { port })
+        Ok(Self {
+            port,
+            txt: Vec::new(),
+        })
+    }
+
+    pub fn with_txt(mut self, key: &str, value: &str) -> Self {
+        self.txt.push((key.to_string(), value.to_string()));
+        self
     }
 
     pub async fn start(&mut self) -> Result<()> {
@@ -43,6 +53,11 @@ impl MdnsServiceWrapper {
         txt_record
             .insert("description", "OTA Update Server")
             .context("Failed to insert description in TXT record")?;
+        for (key, value) in &self.txt {
+            txt_record
+                .insert(key, value)
+                .with_context(|| format!("Failed to insert {} in TXT record", key))?;
+        }
 
         // Set service properties
         service.set_name("OTA Server");
@@ -1,5 +1,26 @@
+use crate::compatibility::Compatibility;
+use crate::versioning::compare_versions;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+// Channel served by `latest.json` and by `/version` when no channel is given
+pub const DEFAULT_CHANNEL: &str = "stable";
+
+// Release state that changes after publishing and is therefore not signed
+// Deltas can be rebuilt and existing releases can be moved into the blob store
+// at any time; either way the image is checked against the signed checksum.
+const UNSIGNED_FIELDS: &[&str] = &[
+    "signature",
+    "channels",
+    "deltas",
+    "blob",
+    "status",
+    "rollbacks",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KernelInfo {
@@ -10,6 +31,194 @@ pub struct KernelInfo {
     pub release_date: DateTime<Utc>,
     pub description: String,
     pub download_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ReleaseSignature>,
+    // Channels this release has been published or promoted to
+    #[serde(default = "default_channels")]
+    pub channels: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compatibility: Option<Compatibility>,
+    // Binary deltas from earlier releases to this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<DeltaInfo>,
+    // Digest of the image in the content-addressed blob store, if imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    #[serde(default, skip_serializing_if = "ReleaseStatus::is_active")]
+    pub status: ReleaseStatus,
+    // Channels rolled back from this release, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rollbacks: Vec<Rollback>,
+    #[serde(flatten)]
+    pub details: ReleaseDetails,
+}
+
+// Release notes and build provenance, set when publishing. Also the format of
+// the sidecar TOML file `add-kernel --details` reads.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseDetails {
+    // Markdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_notes: Option<String>,
+    // CVE IDs fixed by this release, e.g. "CVE-2024-1086"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cves: Vec<String>,
+    // Normal unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+// How urgently devices should install a release
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Normal,
+    Critical,
+    Security,
+}
+
+impl Severity {
+    pub fn is_normal(&self) -> bool {
+        *self == Severity::Normal
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Normal => write!(f, "normal"),
+            Severity::Critical => write!(f, "critical"),
+            Severity::Security => write!(f, "security"),
+        }
+    }
+}
+
+impl ReleaseDetails {
+    // Fields given on the command line replace those from the sidecar file;
+    // CVEs and labels are added to them
+    pub fn merge(&mut self, other: ReleaseDetails) {
+        if other.release_notes.is_some() {
+            self.release_notes = other.release_notes;
+        }
+        for cve in other.cves {
+            if !self.cves.contains(&cve) {
+                self.cves.push(cve);
+            }
+        }
+        if other.severity.is_some() {
+            self.severity = other.severity;
+        }
+        if other.git_commit.is_some() {
+            self.git_commit = other.git_commit;
+        }
+        if other.build_id.is_some() {
+            self.build_id = other.build_id;
+        }
+        self.labels.extend(other.labels);
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for cve in &self.cves {
+            if !is_cve_id(cve) {
+                return Err(format!(
+                    "Invalid CVE ID '{}': expected CVE-<year>-<number>",
+                    cve
+                ));
+            }
+        }
+        if let Some(commit) = &self.git_commit {
+            let valid =
+                (7..=64).contains(&commit.len()) && commit.chars().all(|c| c.is_ascii_hexdigit());
+            if !valid {
+                return Err(format!("Invalid git commit '{}'", commit));
+            }
+        }
+        if let Some(key) = self
+            .labels
+            .keys()
+            .find(|key| key.is_empty() || key.contains(['=', '\n']))
+        {
+            return Err(format!("Invalid label name {:?}", key));
+        }
+        Ok(())
+    }
+}
+
+fn is_cve_id(cve: &str) -> bool {
+    let mut parts = cve.splitn(3, '-');
+    let (Some("CVE"), Some(year), Some(number)) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+    year.len() == 4
+        && year.chars().all(|c| c.is_ascii_digit())
+        && number.len() >= 4
+        && number.chars().all(|c| c.is_ascii_digit())
+}
+
+// A channel moved back from a release to an earlier one, with who did it and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollback {
+    pub channel: String,
+    pub to_version: String,
+    pub by: String,
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+// Whether a release is still offered. A yanked release stays downloadable for
+// devices already running it, but is never offered and never a channel head.
+// A deprecated release is still offered, flagged so devices can warn about it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseStatus {
+    #[default]
+    Active,
+    Deprecated,
+    Yanked,
+}
+
+impl ReleaseStatus {
+    pub fn is_active(&self) -> bool {
+        *self == ReleaseStatus::Active
+    }
+}
+
+impl std::fmt::Display for ReleaseStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseStatus::Active => write!(f, "active"),
+            ReleaseStatus::Deprecated => write!(f, "deprecated"),
+            ReleaseStatus::Yanked => write!(f, "yanked"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaInfo {
+    pub from_version: String,
+    pub delta_file: String,
+    pub algorithm: String,
+    pub file_size: u64,
+    pub checksum: String,
+    // Checksum of the image the delta applies to
+    pub source_checksum: String,
+    pub window_log: u32,
+}
+
+// Detached Ed25519 signatures over the image digest and the release metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseSignature {
+    pub algorithm: String,
+    pub key_fingerprint: String,
+    pub image_signature: String,
+    pub metadata_signature: String,
 }
 
 // Client-facing structure that exactly matches what the OTA client expects
@@ -22,6 +231,38 @@ pub struct ClientKernelInfo {
     pub release_date: String, // Client expects string, not DateTime
     pub description: String,
     pub download_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ReleaseSignature>,
+    // Base64 of the exact bytes covered by `signature.metadata_signature`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compatibility: Option<Compatibility>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<ClientDeltaInfo>,
+    // Immutable, content-addressed download location of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_url: Option<String>,
+    // The release is still offered but should be replaced soon
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deprecated: bool,
+    // The device runs a release its channel was rolled back from and must
+    // install this one even though it is older
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_downgrade: bool,
+}
+
+// A delta as offered to devices; the result must match `target_checksum`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientDeltaInfo {
+    pub base_version: String,
+    pub base_checksum: String,
+    pub target_checksum: String,
+    pub algorithm: String,
+    pub window_log: u32,
+    pub file_size: u64,
+    pub checksum: String,
+    pub download_url: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,7 +274,35 @@ pub struct VersionResponse {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VersionHistory {
     pub versions: Vec<KernelInfo>,
+    // Head of the stable channel, kept for clients that predate channels
     pub latest: String,
+    // Head version of every channel, including stable
+    #[serde(default)]
+    pub channels: BTreeMap<String, String>,
+    // Channels whose head was explicitly forced instead of computed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pinned: BTreeMap<String, String>,
+}
+
+fn default_channels() -> Vec<String> {
+    vec![DEFAULT_CHANNEL.to_string()]
+}
+
+// Channel names end up in URLs and file names, so keep them simple
+pub fn validate_channel_name(channel: &str) -> Result<(), String> {
+    let valid = !channel.is_empty()
+        && channel.len() <= 32
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid channel name '{}': use lowercase letters, digits and '-'",
+            channel
+        ))
+    }
 }
 
 impl KernelInfo {
@@ -52,7 +321,44 @@ impl KernelInfo {
             release_date: Utc::now(),
             description,
             download_url: format!("/kernels/{}", kernel_file),
+            signature: None,
+            channels: default_channels(),
+            compatibility: None,
+            deltas: Vec::new(),
+            blob: None,
+            status: ReleaseStatus::Active,
+            rollbacks: Vec::new(),
+            details: ReleaseDetails::default(),
+        }
+    }
+
+    pub fn is_offered(&self) -> bool {
+        self.status != ReleaseStatus::Yanked
+    }
+
+    pub fn rolled_back_on(&self, channel: &str) -> Option<&Rollback> {
+        self.rollbacks
+            .iter()
+            .rev()
+            .find(|rollback| rollback.channel == channel)
+    }
+
+    pub fn find_delta(&self, from_version: &str) -> Option<&DeltaInfo> {
+        self.deltas
+            .iter()
+            .find(|delta| delta.from_version == from_version)
+    }
+
+    // Canonical bytes covered by the metadata signature: the release as JSON with
+    // sorted keys, minus the signature itself and any mutable release state
+    pub fn signing_payload(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in UNSIGNED_FIELDS {
+                fields.remove(*field);
+            }
         }
+        serde_json::to_vec(&value)
     }
 
     // Convert to client-facing format that exactly matches client expectations
@@ -65,6 +371,185 @@ impl KernelInfo {
             release_date: self.release_date.to_rfc3339(), // Convert DateTime to string
             description: self.description.clone(),
             download_url: self.download_url.clone(),
+            signature: self.signature.clone(),
+            signed_metadata: self
+                .signature
+                .as_ref()
+                .and_then(|_| self.signing_payload().ok())
+                .map(|payload| BASE64.encode(payload)),
+            compatibility: self.compatibility.clone(),
+            deltas: self
+                .deltas
+                .iter()
+                .map(|delta| ClientDeltaInfo {
+                    base_version: delta.from_version.clone(),
+                    base_checksum: delta.source_checksum.clone(),
+                    target_checksum: self.checksum.clone(),
+                    algorithm: delta.algorithm.clone(),
+                    window_log: delta.window_log,
+                    file_size: delta.file_size,
+                    checksum: delta.checksum.clone(),
+                    download_url: format!("/deltas/{}/{}", delta.from_version, self.version),
+                })
+                .collect(),
+            blob_url: self
+                .blob
+                .as_ref()
+                .map(|digest| format!("/blobs/sha256/{}", digest.trim_start_matches("sha256:"))),
+            deprecated: self.status == ReleaseStatus::Deprecated,
+            force_downgrade: false,
+        }
+    }
+}
+
+impl VersionHistory {
+    pub fn empty() -> Self {
+        Self {
+            versions: Vec::new(),
+            latest: "none".to_string(),
+            channels: BTreeMap::new(),
+            pinned: BTreeMap::new(),
+        }
+    }
+
+    pub fn find(&self, version: &str) -> Option<&KernelInfo> {
+        self.position(version).map(|index| &self.versions[index])
+    }
+
+    // Index of a release, by its exact version or else one with the same
+    // precedence, so "v1.2.0" and "1.2.0" name the same release
+    pub fn position(&self, version: &str) -> Option<usize> {
+        self.versions
+            .iter()
+            .position(|v| v.version == version)
+            .or_else(|| {
+                self.versions
+                    .iter()
+                    .position(|v| compare_versions(&v.version, version) == Ordering::Equal)
+            })
+    }
+
+    // Head version of a channel; histories written before channels existed
+    // only know the stable head through `latest`
+    pub fn channel_head(&self, channel: &str) -> Option<&KernelInfo> {
+        let head = match self.channels.get(channel) {
+            Some(head) => head.as_str(),
+            None if channel == DEFAULT_CHANNEL => self.latest.as_str(),
+            None => return None,
+        };
+        self.find(head)
+    }
+
+    // Versions sorted by semver precedence, oldest first
+    pub fn sorted_versions(&self) -> Vec<&KernelInfo> {
+        let mut versions: Vec<&KernelInfo> = self.versions.iter().collect();
+        versions.sort_by(|a, b| compare_versions(&a.version, &b.version));
+        versions
+    }
+
+    // Recompute every channel head as the highest offered version published to
+    // that channel, unless the head has been pinned with `--force-latest`
+    pub fn recompute_heads(&mut self) {
+        let mut heads: BTreeMap<String, String> = BTreeMap::new();
+        for kernel in self.versions.iter().filter(|kernel| kernel.is_offered()) {
+            for channel in &kernel.channels {
+                let is_higher = heads.get(channel).is_none_or(|head| {
+                    compare_versions(&kernel.version, head) == Ordering::Greater
+                });
+                if is_higher {
+                    heads.insert(channel.clone(), kernel.version.clone());
+                }
+            }
+        }
+
+        self.pinned.retain(|_, version| {
+            self.versions
+                .iter()
+                .any(|v| &v.version == version && v.is_offered())
+        });
+        for (channel, version) in &self.pinned {
+            heads.insert(channel.clone(), version.clone());
+        }
+
+        self.latest = heads
+            .get(DEFAULT_CHANNEL)
+            .cloned()
+            .unwrap_or_else(|| "none".to_string());
+        self.channels = heads;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_details() {
+        // Releases written before release details existed still load, and
+        // serialize (and sign) exactly as before
+        let json = r#"{
+            "version": "1.0.0",
+            "kernel_file": "kernel-v1.0.0.img",
+            "file_size": 21,
+            "checksum": "sha256:00",
+            "release_date": "2025-06-15T05:14:20Z",
+            "description": "Old release",
+            "download_url": "/kernels/kernel-v1.0.0.img"
+        }"#;
+        let kernel: KernelInfo = serde_json::from_str(json).unwrap();
+        assert_eq!(kernel.details, ReleaseDetails::default());
+        let value = serde_json::to_value(&kernel).unwrap();
+        for field in ["release_notes", "cves", "severity", "git_commit", "labels"] {
+            assert!(value.get(field).is_none(), "{} was serialized", field);
+        }
+
+        let sidecar = r#"
+            release_notes = """
+            ## Fixes
+            - USB resets on resume
+            """
+            cves = ["CVE-2024-1086"]
+            severity = "security"
+            git_commit = "3f2a9c1"
+            [labels]
+            toolchain = "gcc-13"
+        "#;
+        let mut details: ReleaseDetails = toml::from_str(sidecar).unwrap();
+        details.validate().unwrap();
+        details.merge(ReleaseDetails {
+            cves: vec!["CVE-2024-1086".to_string(), "CVE-2024-26581".to_string()],
+            build_id: Some("ci-1234".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(details.cves, vec!["CVE-2024-1086", "CVE-2024-26581"]);
+        assert_eq!(details.severity, Some(Severity::Security));
+
+        // An explicit normal severity overrides the sidecar too
+        let mut lowered = details.clone();
+        lowered.merge(ReleaseDetails {
+            severity: Some(Severity::Normal),
+            ..Default::default()
+        });
+        assert_eq!(lowered.severity, Some(Severity::Normal));
+        assert_eq!(details.build_id.as_deref(), Some("ci-1234"));
+
+        let mut kernel = kernel;
+        kernel.details = details;
+        let round_trip: KernelInfo =
+            serde_json::from_str(&serde_json::to_string(&kernel).unwrap()).unwrap();
+        assert_eq!(round_trip.details, kernel.details);
+        assert_eq!(round_trip.details.labels["toolchain"], "gcc-13");
+
+        for cves in [
+            vec!["CVE-24-1086"],
+            vec!["cve-2024-1086"],
+            vec!["CVE-2024-12"],
+        ] {
+            let details = ReleaseDetails {
+                cves: cves.into_iter().map(str::to_string).collect(),
+                ..Default::default()
+            };
+            assert!(details.validate().is_err());
         }
     }
 }
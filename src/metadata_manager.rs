@@ -1,19 +1,78 @@
+use crate::atomic_file::{FileLock, write_atomic};
+use crate::blob_store::{BlobEntry, BlobStore};
 use crate::checksum::calculate_file_checksum;
-use crate::metadata::{KernelInfo, VersionHistory};
+use crate::compatibility::Compatibility;
+use crate::config::ServerConfig;
+use crate::delta::{DELTA_ALGORITHM, apply_delta, create_delta, delta_file_name, window_log};
+use crate::metadata::{
+    DEFAULT_CHANNEL, DeltaInfo, KernelInfo, ReleaseDetails, ReleaseStatus, Rollback,
+    VersionHistory, validate_channel_name,
+};
+use crate::metadata_store::{MetadataStore, StoreBackend, open_backend, open_store};
+use crate::repair::{RepairPlan, plan_repair};
+use crate::rollout::{FailureBudget, RolloutPause, RolloutState};
+use crate::signing::ReleaseSigner;
+use crate::versioning::{compare_versions, parse_version};
 use anyhow::Result;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
 
+// How a new release is published
+#[derive(Debug, Clone)]
+pub struct ReleaseOptions {
+    pub channel: String,
+    pub force_latest: bool,
+    pub compatibility: Option<Compatibility>,
+    // Initial staged rollout percentage; releases default to the whole fleet
+    pub rollout: Option<u8>,
+    // Overrides the default failure budget that pauses a bad rollout
+    pub failure_budget: Option<FailureBudget>,
+    // Number of preceding releases to build binary deltas from
+    pub delta_predecessors: usize,
+    // Treat the kernel file as a path to copy into the blob store
+    pub import: bool,
+    // Release notes and build provenance
+    pub details: ReleaseDetails,
+}
+
+// Releases a new kernel gets deltas from unless told otherwise
+pub const DEFAULT_DELTA_PREDECESSORS: usize = 3;
+
+impl Default for ReleaseOptions {
+    fn default() -> Self {
+        Self {
+            channel: DEFAULT_CHANNEL.to_string(),
+            force_latest: false,
+            compatibility: None,
+            rollout: None,
+            failure_budget: None,
+            delta_predecessors: DEFAULT_DELTA_PREDECESSORS,
+            import: false,
+            details: ReleaseDetails::default(),
+        }
+    }
+}
+
 pub struct MetadataManager {
     kernels_dir: PathBuf,
     metadata_dir: PathBuf,
+    deltas_dir: PathBuf,
+    blob_store: BlobStore,
+    store: Arc<dyn MetadataStore>,
 }
 
 impl MetadataManager {
-    pub fn new(kernels_dir: String, metadata_dir: String) -> Self {
+    pub fn new(config: &ServerConfig) -> Self {
+        let paths = &config.paths;
         Self {
-            kernels_dir: PathBuf::from(kernels_dir),
-            metadata_dir: PathBuf::from(metadata_dir),
+            kernels_dir: PathBuf::from(&paths.kernels_dir),
+            metadata_dir: PathBuf::from(&paths.metadata_dir),
+            deltas_dir: PathBuf::from(&paths.deltas_dir),
+            blob_store: BlobStore::new(&paths.blobs_dir),
+            store: open_store(config),
         }
     }
 
@@ -22,7 +81,68 @@ impl MetadataManager {
         version: String,
         kernel_file: String,
         description: String,
-    ) -> Result<()> {
+        options: ReleaseOptions,
+        signer: Option<&ReleaseSigner>,
+    ) -> Result<KernelInfo> {
+        let ReleaseOptions {
+            channel,
+            force_latest,
+            compatibility,
+            rollout,
+            failure_budget,
+            delta_predecessors,
+            import,
+            details,
+        } = options;
+        validate_channel_name(&channel).map_err(anyhow::Error::msg)?;
+        details.validate().map_err(anyhow::Error::msg)?;
+        if let Some(compatibility) = &compatibility {
+            compatibility.validate().map_err(anyhow::Error::msg)?;
+        }
+        if let Some(budget) = &failure_budget {
+            validate_failure_budget(budget)?;
+        }
+        // Stored without the optional leading `v`
+        let version = parse_version(&version)
+            .map_err(|e| anyhow::anyhow!("Invalid semantic version '{}': {}", version, e))?
+            .to_string();
+
+        let history = self.list_versions().await?;
+        check_new_version(&history, &version)?;
+
+        // Deltas can take minutes for large images, so they are built before
+        // taking the metadata lock and only committed with the release
+        let image_path = if import {
+            PathBuf::from(&kernel_file)
+        } else {
+            self.kernels_dir.join(&kernel_file)
+        };
+        if !image_path.is_file() {
+            return Err(anyhow::anyhow!("Kernel file not found: {}", kernel_file));
+        }
+        let image_checksum = calculate_file_checksum(&image_path).await?;
+        let deltas = self
+            .build_deltas(
+                &history,
+                &version,
+                &image_path,
+                &image_checksum,
+                delta_predecessors,
+            )
+            .await?;
+
+        // Hold the metadata lock for the rest of the publish, so concurrent
+        // publishes and `gc` see either none or all of this release
+        let mut txn = self.begin().await?;
+        check_new_version(&txn.history, &version)?;
+
+        let (kernel_file, blob) = if import {
+            let (kernel_file, digest) = self.import_kernel(&kernel_file, &txn.history).await?;
+            (kernel_file, Some(digest))
+        } else {
+            (kernel_file, None)
+        };
+
         let kernel_path = self.kernels_dir.join(&kernel_file);
 
         if !kernel_path.exists() {
@@ -33,9 +153,15 @@ impl MetadataManager {
         let metadata = fs::metadata(&kernel_path).await?;
         let file_size = metadata.len();
         let checksum = calculate_file_checksum(&kernel_path).await?;
+        if checksum != image_checksum {
+            return Err(anyhow::anyhow!(
+                "Kernel file {} changed while its deltas were built",
+                kernel_file
+            ));
+        }
 
         // Create kernel info
-        let kernel_info = KernelInfo::new(
+        let mut kernel_info = KernelInfo::new(
             version.clone(),
             kernel_file,
             file_size,
@@ -43,68 +169,865 @@ impl MetadataManager {
             description,
         );
 
-        // Update latest.json
-        self.update_latest(&kernel_info).await?;
+        kernel_info.channels = vec![channel.clone()];
+        kernel_info.blob = blob;
+        kernel_info.compatibility = compatibility.filter(|c| !c.is_empty());
+        kernel_info.details = details;
 
-        // Update version history
-        self.update_history(&kernel_info).await?;
+        // A predecessor may have been removed or replaced in the meantime
+        kernel_info.deltas = deltas
+            .into_iter()
+            .filter(|delta| {
+                txn.history
+                    .find(&delta.from_version)
+                    .is_some_and(|source| source.checksum == delta.source_checksum)
+            })
+            .collect();
 
-        Ok(())
+        // Sign once all release fields are final
+        if let Some(signer) = signer {
+            kernel_info.signature = Some(signer.sign_release(&kernel_info)?);
+        }
+
+        if let Some(percentage) = rollout {
+            validate_percentage(percentage)?;
+            txn.rollouts.set_percentage(&version, percentage);
+        }
+        if let Some(budget) = failure_budget {
+            txn.rollouts.set_failure_budget(&version, budget);
+        }
+        insert_release(&mut txn.history, &kernel_info, &channel, force_latest);
+        txn.commit().await?;
+
+        Ok(kernel_info)
     }
 
-    async fn update_latest(&self, kernel_info: &KernelInfo) -> Result<()> {
-        let latest_path = self.metadata_dir.join("latest.json");
-        let json = serde_json::to_string_pretty(kernel_info)?;
-        fs::write(latest_path, json).await?;
-        Ok(())
+    // Copy an image into the blob store and expose it in kernels_dir under its
+    // file name, hard-linked to the blob. Returns the file name and digest.
+    async fn import_kernel(
+        &self,
+        source: &str,
+        history: &VersionHistory,
+    ) -> Result<(String, String)> {
+        let source = PathBuf::from(source);
+        let kernel_file = source
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow::anyhow!("Invalid kernel file path: {}", source.display()))?;
+        if !source.is_file() {
+            return Err(anyhow::anyhow!(
+                "Kernel file not found: {}",
+                source.display()
+            ));
+        }
+
+        let digest = self.blob_store.import(&source).await?;
+
+        // Never swap the image out from under a release that serves this file
+        if let Some(other) = history
+            .versions
+            .iter()
+            .find(|kernel| kernel.kernel_file == kernel_file && kernel.checksum != digest)
+        {
+            return Err(anyhow::anyhow!(
+                "Kernel file {} is already used by version {} with different content",
+                kernel_file,
+                other.version
+            ));
+        }
+
+        fs::create_dir_all(&self.kernels_dir).await?;
+        self.blob_store
+            .link_into(&digest, &self.kernels_dir.join(&kernel_file))
+            .await?;
+        Ok((kernel_file, digest))
     }
 
-    async fn update_history(&self, kernel_info: &KernelInfo) -> Result<()> {
-        let history_path = self.metadata_dir.join("version-history.json");
+    // Remove blobs that no release references. Releases reference a blob by
+    // checksum, so images added without `--import` keep a matching blob alive.
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<Vec<BlobEntry>> {
+        // Under the lock, so a blob imported by a publish in progress is never
+        // collected before its release is committed
+        let txn = self.begin().await?;
+        let history = &txn.history;
+        let mut removed = Vec::new();
+        for blob in self.blob_store.list().await? {
+            let referenced = history
+                .versions
+                .iter()
+                .any(|kernel| kernel.checksum == blob.digest);
+            if referenced {
+                continue;
+            }
+            if !dry_run {
+                self.blob_store.remove(&blob.digest).await?;
+            }
+            removed.push(blob);
+        }
+        Ok(removed)
+    }
 
-        let mut history = if history_path.exists() {
-            let content = fs::read_to_string(&history_path).await?;
-            serde_json::from_str::<VersionHistory>(&content).unwrap_or_else(|_| VersionHistory {
-                versions: Vec::new(),
-                latest: kernel_info.version.clone(),
+    // Precompute deltas from the closest preceding releases to a new release.
+    // Predecessors whose image is missing or identical are skipped.
+    async fn build_deltas(
+        &self,
+        history: &VersionHistory,
+        version: &str,
+        image_path: &Path,
+        checksum: &str,
+        count: usize,
+    ) -> Result<Vec<DeltaInfo>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let predecessors: Vec<&KernelInfo> = history
+            .sorted_versions()
+            .into_iter()
+            .rev()
+            .filter(|kernel| {
+                compare_versions(&kernel.version, version) == Ordering::Less
+                    && kernel.checksum != checksum
             })
-        } else {
-            VersionHistory {
-                versions: Vec::new(),
-                latest: kernel_info.version.clone(),
+            .filter(|kernel| self.kernels_dir.join(&kernel.kernel_file).exists())
+            .take(count)
+            .collect();
+        if predecessors.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        fs::create_dir_all(&self.deltas_dir).await?;
+        // Read once and shared with every delta computation
+        let target_image: Arc<[u8]> = fs::read(image_path).await?.into();
+
+        let mut deltas = Vec::new();
+        for source in predecessors {
+            let source_image = fs::read(self.kernels_dir.join(&source.kernel_file)).await?;
+            // Devices hold the image as published; a changed file is no base for them
+            if format!("sha256:{:x}", Sha256::digest(&source_image)) != source.checksum {
+                continue;
             }
-        };
+            let window_log = window_log(source_image.len(), target_image.len());
+            let target_len = target_image.len();
+            let target_image = Arc::clone(&target_image);
+            let delta = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+                let delta = create_delta(&source_image, &target_image)?;
+                // Never publish a delta that does not reproduce the target
+                if *apply_delta(&source_image, &delta)? != *target_image {
+                    return Err(anyhow::anyhow!("Delta does not reproduce the target image"));
+                }
+                Ok(delta)
+            })
+            .await??;
+            // Unrelated images don't diff; devices are better off with the full image
+            if delta.len() >= target_len {
+                continue;
+            }
+
+            let delta_file = delta_file_name(&source.version, version);
+            write_atomic(&self.deltas_dir.join(&delta_file), &delta).await?;
+            deltas.push(DeltaInfo {
+                from_version: source.version.clone(),
+                delta_file,
+                algorithm: DELTA_ALGORITHM.to_string(),
+                file_size: delta.len() as u64,
+                checksum: format!("sha256:{:x}", Sha256::digest(&delta)),
+                source_checksum: source.checksum.clone(),
+                window_log,
+            });
+        }
+        Ok(deltas)
+    }
+
+    // Publish an existing release to another channel. The channel head only moves
+    // to it if it is the highest version there, unless `force_latest` is set.
+    pub async fn promote(
+        &self,
+        version: &str,
+        channel: &str,
+        force_latest: bool,
+    ) -> Result<KernelInfo> {
+        validate_channel_name(channel).map_err(anyhow::Error::msg)?;
+
+        let mut txn = self.begin().await?;
+        let index = txn
+            .history
+            .position(version)
+            .ok_or_else(|| anyhow::anyhow!("Kernel version not found: {}", version))?;
+        let kernel_info = &mut txn.history.versions[index];
+
+        if !kernel_info.channels.iter().any(|c| c == channel) {
+            kernel_info.channels.push(channel.to_string());
+        }
+        let kernel_info = kernel_info.clone();
+
+        set_pin(
+            &mut txn.history,
+            channel,
+            &kernel_info.version,
+            force_latest,
+        );
+        txn.history.recompute_heads();
+        txn.commit().await?;
+
+        Ok(kernel_info)
+    }
+
+    // Yank, deprecate or restore a release. Channel heads move to the highest
+    // release that is still offered; unless forced, this refuses to leave the
+    // stable channel without any release to offer.
+    pub async fn set_status(
+        &self,
+        version: &str,
+        status: ReleaseStatus,
+        force: bool,
+    ) -> Result<KernelInfo> {
+        let mut txn = self.begin().await?;
+        let index = txn
+            .history
+            .position(version)
+            .ok_or_else(|| anyhow::anyhow!("Kernel version not found: {}", version))?;
+        let kernel_info = &mut txn.history.versions[index];
+        kernel_info.status = status;
+        let kernel_info = kernel_info.clone();
+
+        let had_stable = txn.history.channel_head(DEFAULT_CHANNEL).is_some();
+        txn.history.recompute_heads();
+        if !force {
+            check_stable_remains(had_stable, &txn.history, &kernel_info.version)?;
+        }
+        txn.commit().await?;
+        Ok(kernel_info)
+    }
 
-        // Add new version or update existing
-        if let Some(pos) = history
+    // Drop a release from the history, and with `delete_image` also its image
+    // (unless another release uses the same file) and its deltas. Like
+    // set_status, it refuses to remove the last stable release unless forced.
+    // Returns the removed release and whether its image was deleted.
+    pub async fn remove_release(
+        &self,
+        version: &str,
+        delete_image: bool,
+        force: bool,
+    ) -> Result<(KernelInfo, bool)> {
+        let mut txn = self.begin().await?;
+        let index = txn
+            .history
+            .position(version)
+            .ok_or_else(|| anyhow::anyhow!("Kernel version not found: {}", version))?;
+        let had_stable = txn.history.channel_head(DEFAULT_CHANNEL).is_some();
+        let kernel_info = txn.history.versions.remove(index);
+        txn.rollouts.releases.remove(&kernel_info.version);
+        txn.history.recompute_heads();
+        if !force {
+            check_stable_remains(had_stable, &txn.history, &kernel_info.version)?;
+        }
+        let shared_image = txn
+            .history
             .versions
             .iter()
-            .position(|v| v.version == kernel_info.version)
-        {
-            history.versions[pos] = kernel_info.clone();
-        } else {
-            history.versions.push(kernel_info.clone());
+            .any(|v| v.kernel_file == kernel_info.kernel_file);
+        txn.save().await?;
+        if !delete_image {
+            return Ok((kernel_info, false));
         }
 
-        history.latest = kernel_info.version.clone();
+        // Still under the lock, so a release published meanwhile cannot start
+        // using a file that is about to go
+        let mut files = Vec::new();
+        if !shared_image {
+            files.push(self.kernels_dir.join(&kernel_info.kernel_file));
+        }
+        files.extend(
+            kernel_info
+                .deltas
+                .iter()
+                .map(|delta| self.deltas_dir.join(&delta.delta_file)),
+        );
+        for file in files {
+            match fs::remove_file(&file).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        drop(txn);
+        Ok((kernel_info, !shared_image))
+    }
+
+    // Point channels back at an earlier release by pinning it as their head,
+    // recording on each release rolled back from who did it and why. Without a
+    // channel, every channel of the target release that is on a newer release
+    // is rolled back. The releases rolled back from leave the channel, so a
+    // later publish never makes them its head again; with `yank` they are also
+    // yanked. Returns each channel with the version it was rolled back from.
+    pub async fn rollback(
+        &self,
+        to_version: &str,
+        channel: Option<&str>,
+        yank: bool,
+        by: &str,
+        reason: &str,
+    ) -> Result<Vec<(String, String)>> {
+        if reason.trim().is_empty() {
+            return Err(anyhow::anyhow!("A rollback needs a reason"));
+        }
+        if let Some(channel) = channel {
+            validate_channel_name(channel).map_err(anyhow::Error::msg)?;
+        }
 
-        let json = serde_json::to_string_pretty(&history)?;
-        fs::write(history_path, json).await?;
-        Ok(())
+        let mut txn = self.begin().await?;
+        let target = txn
+            .history
+            .find(to_version)
+            .ok_or_else(|| anyhow::anyhow!("Kernel version not found: {}", to_version))?;
+        let to_version = target.version.clone();
+        if !target.is_offered() {
+            return Err(anyhow::anyhow!(
+                "Cannot roll back to version {}, it is {}",
+                to_version,
+                target.status
+            ));
+        }
+        let channels = match channel {
+            Some(channel) if !target.channels.iter().any(|c| c == channel) => {
+                return Err(anyhow::anyhow!(
+                    "Version {} is not published to channel {}",
+                    to_version,
+                    channel
+                ));
+            }
+            Some(channel) => vec![channel.to_string()],
+            None => target.channels.clone(),
+        };
+
+        let mut rolled_back = Vec::new();
+        for channel in channels {
+            let Some(head) = txn.history.channel_head(&channel) else {
+                continue;
+            };
+            if compare_versions(&head.version, &to_version) == Ordering::Greater {
+                let from_version = head.version.clone();
+                rolled_back.push((channel, from_version));
+            }
+        }
+        if rolled_back.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No channel is on a release newer than {}",
+                to_version
+            ));
+        }
+
+        let at = chrono::Utc::now();
+        for (channel, from_version) in &rolled_back {
+            if let Some(kernel) = txn
+                .history
+                .versions
+                .iter_mut()
+                .find(|v| &v.version == from_version)
+            {
+                kernel.rollbacks.push(Rollback {
+                    channel: channel.clone(),
+                    to_version: to_version.clone(),
+                    by: by.to_string(),
+                    reason: reason.to_string(),
+                    at,
+                });
+                kernel.channels.retain(|c| c != channel);
+                if yank {
+                    kernel.status = ReleaseStatus::Yanked;
+                }
+            }
+            set_pin(&mut txn.history, channel, &to_version, true);
+        }
+        txn.history.recompute_heads();
+        txn.commit().await?;
+
+        Ok(rolled_back)
+    }
+
+    // Change how much of the fleet is offered a release
+    pub async fn set_rollout(&self, version: &str, percentage: u8) -> Result<()> {
+        validate_percentage(percentage)?;
+        let mut txn = self.begin().await?;
+        let version = txn
+            .history
+            .find(version)
+            .map(|kernel| kernel.version.clone())
+            .ok_or_else(|| anyhow::anyhow!("Kernel version not found: {}", version))?;
+        txn.rollouts.set_percentage(&version, percentage);
+        txn.commit().await
+    }
+
+    // Stop offering a release whose failure budget was exceeded
+    pub async fn pause_rollout(&self, version: &str, pause: RolloutPause) -> Result<()> {
+        let mut txn = self.begin().await?;
+        txn.rollouts.pause(version, pause);
+        txn.commit().await
+    }
+
+    // Offer a paused release again
+    pub async fn resume_rollout(&self, version: &str) -> Result<()> {
+        let mut txn = self.begin().await?;
+        let version = txn
+            .history
+            .find(version)
+            .map_or(version.to_string(), |kernel| kernel.version.clone());
+        if !txn.rollouts.resume(&version) {
+            return Err(anyhow::anyhow!(
+                "Rollout of version {} is not paused",
+                version
+            ));
+        }
+        txn.commit().await
+    }
+
+    // Lock the metadata directory and load the current state. All metadata
+    // changes go through a transaction.
+    pub async fn begin(&self) -> Result<MetadataTransaction> {
+        let lock = self.lock().await?;
+
+        let history = self.list_versions().await?;
+        let rollouts = self.load_rollouts().await?;
+        Ok(MetadataTransaction {
+            _lock: lock,
+            store: self.store.clone(),
+            original_history: serde_json::to_string_pretty(&history)?,
+            original_rollouts: serde_json::to_string_pretty(&rollouts)?,
+            history,
+            rollouts,
+        })
+    }
+
+    async fn lock(&self) -> Result<FileLock> {
+        fs::create_dir_all(&self.metadata_dir).await?;
+        FileLock::exclusive(&self.metadata_dir.join(LOCK_FILE)).await
+    }
+
+    // Rebuild the version history from surviving metadata and kernels_dir.
+    // Nothing is written unless `write` is set; a corrupt history is then
+    // moved to metadata/quarantine rather than overwritten.
+    pub async fn repair(&self, write: bool) -> Result<(RepairPlan, Option<PathBuf>)> {
+        if self.store.backend() != StoreBackend::Json {
+            return Err(anyhow::anyhow!(
+                "repair works on the json metadata files; the configured backend is {}",
+                self.store.backend()
+            ));
+        }
+        let lock = self.lock().await?;
+        let plan = plan_repair(&self.kernels_dir, &self.metadata_dir).await?;
+        if !write {
+            return Ok((plan, None));
+        }
+
+        let rollouts = self.load_rollouts().await?;
+        let quarantined = match plan.corruption {
+            Some(_) => Some(self.quarantine("version-history.json").await?),
+            None => None,
+        };
+        let txn = MetadataTransaction {
+            _lock: lock,
+            store: self.store.clone(),
+            original_history: String::new(),
+            original_rollouts: serde_json::to_string_pretty(&rollouts)?,
+            history: plan.history.clone(),
+            rollouts,
+        };
+        txn.commit().await?;
+        Ok((plan, quarantined))
+    }
+
+    async fn quarantine(&self, file_name: &str) -> Result<PathBuf> {
+        let quarantine_dir = self.metadata_dir.join("quarantine");
+        fs::create_dir_all(&quarantine_dir).await?;
+        let target = quarantine_dir.join(format!(
+            "{}.{}",
+            file_name,
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        ));
+        fs::rename(self.metadata_dir.join(file_name), &target).await?;
+        Ok(target)
+    }
+
+    pub async fn load_rollouts(&self) -> Result<RolloutState> {
+        self.store.load_rollouts().await
     }
 
     pub async fn list_versions(&self) -> Result<VersionHistory> {
-        let history_path = self.metadata_dir.join("version-history.json");
+        self.store.load_history().await
+    }
 
-        if history_path.exists() {
-            let content = fs::read_to_string(&history_path).await?;
-            let history = serde_json::from_str::<VersionHistory>(&content)?;
-            Ok(history)
-        } else {
-            Ok(VersionHistory {
-                versions: Vec::new(),
-                latest: "none".to_string(),
-            })
+    // Copy all metadata from one backend to another under the metadata lock,
+    // then read it back to make sure nothing was lost. Returns the number of
+    // releases and rollout entries copied.
+    pub async fn migrate_store(
+        &self,
+        config: &ServerConfig,
+        from: StoreBackend,
+        to: StoreBackend,
+    ) -> Result<(usize, usize)> {
+        if from == to {
+            return Err(anyhow::anyhow!(
+                "Source and target backend are both {}",
+                from
+            ));
         }
+        let source = open_backend(config, from);
+        let target = open_backend(config, to);
+        let _lock = self.lock().await?;
+
+        let history = source.load_history().await?;
+        let rollouts = source.load_rollouts().await?;
+        target.save(Some(&history), Some(&rollouts)).await?;
+
+        let copied_history = target.load_history().await?;
+        let copied_rollouts = target.load_rollouts().await?;
+        if serde_json::to_value(&copied_history)? != serde_json::to_value(&history)?
+            || serde_json::to_value(&copied_rollouts)? != serde_json::to_value(&rollouts)?
+        {
+            return Err(anyhow::anyhow!(
+                "Metadata read back from the {} backend differs from the {} backend",
+                to,
+                from
+            ));
+        }
+        Ok((history.versions.len(), rollouts.releases.len()))
+    }
+}
+
+const LOCK_FILE: &str = ".metadata.lock";
+
+// Exclusive, locked view of the metadata. Changes are saved to the store on
+// commit; dropping a transaction discards them and releases the lock.
+pub struct MetadataTransaction {
+    _lock: FileLock,
+    store: Arc<dyn MetadataStore>,
+    original_history: String,
+    original_rollouts: String,
+    pub history: VersionHistory,
+    pub rollouts: RolloutState,
+}
+
+impl MetadataTransaction {
+    pub async fn commit(self) -> Result<()> {
+        self.save().await
+    }
+
+    // Save the changes but keep holding the lock, for work that must finish
+    // before anyone else sees the new state
+    async fn save(&self) -> Result<()> {
+        let history = serde_json::to_string_pretty(&self.history)?;
+        let rollouts = serde_json::to_string_pretty(&self.rollouts)?;
+        self.store
+            .save(
+                (history != self.original_history).then_some(&self.history),
+                (rollouts != self.original_rollouts).then_some(&self.rollouts),
+            )
+            .await
+    }
+}
+
+// Add a new release to the history
+fn insert_release(
+    history: &mut VersionHistory,
+    kernel_info: &KernelInfo,
+    channel: &str,
+    force_latest: bool,
+) {
+    history.versions.push(kernel_info.clone());
+    set_pin(history, channel, &kernel_info.version, force_latest);
+    history.recompute_heads();
+}
+
+// A published release is never replaced: that would reset its status and
+// rollbacks, and change the image devices verified. Nor may two releases share
+// a precedence (e.g. 1.0.2+b1 and 1.0.2+b2), which would make picking a head
+// between them arbitrary.
+fn check_new_version(history: &VersionHistory, version: &str) -> Result<()> {
+    match history.find(version) {
+        Some(existing) if existing.version == version => Err(anyhow::anyhow!(
+            "Kernel version {} already exists (remove it first, or promote it to another channel)",
+            version
+        )),
+        Some(existing) => Err(anyhow::anyhow!(
+            "Kernel version {} has the same precedence as the published version {}",
+            version,
+            existing.version
+        )),
+        None => Ok(()),
+    }
+}
+
+fn validate_percentage(percentage: u8) -> Result<()> {
+    if percentage > 100 {
+        return Err(anyhow::anyhow!(
+            "Rollout percentage must be between 0 and 100, got {}",
+            percentage
+        ));
+    }
+    Ok(())
+}
+
+// Devices on the stable channel must always have a release to fall back to
+fn check_stable_remains(had_stable: bool, history: &VersionHistory, version: &str) -> Result<()> {
+    if had_stable && !history.channels.contains_key(DEFAULT_CHANNEL) {
+        return Err(anyhow::anyhow!(
+            "Version {} is the only remaining {} release (force is required)",
+            version,
+            DEFAULT_CHANNEL
+        ));
+    }
+    Ok(())
+}
+
+// A forced head stays pinned until the next regular publish to that channel
+fn set_pin(history: &mut VersionHistory, channel: &str, version: &str, force_latest: bool) {
+    if force_latest {
+        history
+            .pinned
+            .insert(channel.to_string(), version.to_string());
+    } else {
+        history.pinned.remove(channel);
+    }
+}
+
+fn validate_failure_budget(budget: &FailureBudget) -> Result<()> {
+    if !(0.0..=100.0).contains(&budget.max_failure_percent) {
+        return Err(anyhow::anyhow!(
+            "Failure budget must be between 0 and 100 percent, got {}",
+            budget.max_failure_percent
+        ));
+    }
+    if budget.window_minutes == 0 {
+        return Err(anyhow::anyhow!(
+            "Failure window must be at least one minute"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Paths;
+
+    fn test_manager(dir: &Path) -> MetadataManager {
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        MetadataManager::new(&ServerConfig {
+            paths: Paths {
+                kernels_dir: path("kernels"),
+                metadata_dir: path("metadata"),
+                deltas_dir: path("deltas"),
+                blobs_dir: path("blobs"),
+            },
+            ..Default::default()
+        })
+    }
+
+    async fn publish(manager: &MetadataManager, version: &str, file: &str, channel: &str) {
+        manager
+            .add_kernel(
+                version.to_string(),
+                file.to_string(),
+                String::new(),
+                ReleaseOptions {
+                    channel: channel.to_string(),
+                    delta_predecessors: 0,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stable_release_remains() {
+        let dir = std::env::temp_dir().join(format!("metadata-manager-{}", uuid::Uuid::new_v4()));
+        let manager = test_manager(&dir);
+        fs::create_dir_all(&manager.kernels_dir).await.unwrap();
+        fs::write(manager.kernels_dir.join("a.img"), b"kernel-a")
+            .await
+            .unwrap();
+        fs::write(manager.kernels_dir.join("b.img"), b"kernel-b")
+            .await
+            .unwrap();
+        publish(&manager, "1.0.0", "a.img", "stable").await;
+        publish(&manager, "2.0.0", "b.img", "beta").await;
+
+        // Neither yanking nor removing may leave stable without a release
+        assert!(
+            manager
+                .set_status("1.0.0", ReleaseStatus::Yanked, false)
+                .await
+                .is_err()
+        );
+        assert!(manager.remove_release("1.0.0", false, false).await.is_err());
+        let history = manager.list_versions().await.unwrap();
+        assert_eq!(
+            history.channel_head(DEFAULT_CHANNEL).unwrap().version,
+            "1.0.0"
+        );
+
+        // Other channels may run empty
+        manager
+            .set_status("2.0.0", ReleaseStatus::Yanked, false)
+            .await
+            .unwrap();
+
+        let (removed, image_deleted) = manager.remove_release("1.0.0", false, true).await.unwrap();
+        assert_eq!(removed.version, "1.0.0");
+        assert!(!image_deleted);
+        assert!(manager.kernels_dir.join("a.img").exists());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_shared_image() {
+        let dir = std::env::temp_dir().join(format!("metadata-manager-{}", uuid::Uuid::new_v4()));
+        let manager = test_manager(&dir);
+        fs::create_dir_all(&manager.kernels_dir).await.unwrap();
+        let image = manager.kernels_dir.join("shared.img");
+        fs::write(&image, b"kernel").await.unwrap();
+        publish(&manager, "1.0.0", "shared.img", "stable").await;
+        publish(&manager, "1.0.1", "shared.img", "beta").await;
+
+        // The image stays while another release still serves it
+        let (_, image_deleted) = manager.remove_release("1.0.1", true, false).await.unwrap();
+        assert!(!image_deleted);
+        assert!(image.exists());
+
+        let (_, image_deleted) = manager.remove_release("1.0.0", true, true).await.unwrap();
+        assert!(image_deleted);
+        assert!(!image.exists());
+        assert!(manager.list_versions().await.unwrap().versions.is_empty());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publish_existing_version() {
+        let dir = std::env::temp_dir().join(format!("metadata-manager-{}", uuid::Uuid::new_v4()));
+        let manager = test_manager(&dir);
+        fs::create_dir_all(&manager.kernels_dir).await.unwrap();
+        fs::write(manager.kernels_dir.join("a.img"), b"kernel-a")
+            .await
+            .unwrap();
+        fs::write(manager.kernels_dir.join("b.img"), b"kernel-b")
+            .await
+            .unwrap();
+        publish(&manager, "1.0.0", "a.img", "stable").await;
+        publish(&manager, "1.1.0", "b.img", "stable").await;
+        manager
+            .set_status("1.1.0", ReleaseStatus::Yanked, false)
+            .await
+            .unwrap();
+
+        // Publishing 1.1.0 again must not bring it back
+        let result = manager
+            .add_kernel(
+                "1.1.0".to_string(),
+                "a.img".to_string(),
+                String::new(),
+                ReleaseOptions::default(),
+                None,
+            )
+            .await;
+        assert!(result.is_err());
+        let history = manager.list_versions().await.unwrap();
+        let kernel = history.find("1.1.0").unwrap();
+        assert_eq!(kernel.status, ReleaseStatus::Yanked);
+        assert_eq!(kernel.kernel_file, "b.img");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publish_equal_precedence() {
+        let dir = std::env::temp_dir().join(format!("metadata-manager-{}", uuid::Uuid::new_v4()));
+        let manager = test_manager(&dir);
+        fs::create_dir_all(&manager.kernels_dir).await.unwrap();
+        fs::write(manager.kernels_dir.join("a.img"), b"kernel-a")
+            .await
+            .unwrap();
+        fs::write(manager.kernels_dir.join("b.img"), b"kernel-b")
+            .await
+            .unwrap();
+        publish(&manager, "v1.0.2+b1", "a.img", "stable").await;
+
+        let history = manager.list_versions().await.unwrap();
+        assert_eq!(history.versions[0].version, "1.0.2+b1");
+        assert_eq!(history.find("v1.0.2").unwrap().version, "1.0.2+b1");
+
+        for version in ["1.0.2+b1", "v1.0.2+b1", "1.0.2", "1.0.2+b2"] {
+            let result = manager
+                .add_kernel(
+                    version.to_string(),
+                    "b.img".to_string(),
+                    String::new(),
+                    ReleaseOptions::default(),
+                    None,
+                )
+                .await;
+            assert!(result.is_err(), "{} was published", version);
+        }
+        assert_eq!(manager.list_versions().await.unwrap().versions.len(), 1);
+
+        // Other commands accept any spelling of the version
+        manager.set_rollout("v1.0.2", 50).await.unwrap();
+        let rollouts = manager.load_rollouts().await.unwrap();
+        assert_eq!(rollouts.percentage("1.0.2+b1"), 50);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publish_after_rollback() {
+        let dir = std::env::temp_dir().join(format!("metadata-manager-{}", uuid::Uuid::new_v4()));
+        let manager = test_manager(&dir);
+        fs::create_dir_all(&manager.kernels_dir).await.unwrap();
+        for (version, file) in [("1.0.0", "a.img"), ("1.1.0", "b.img"), ("1.2.0", "c.img")] {
+            fs::write(manager.kernels_dir.join(file), version)
+                .await
+                .unwrap();
+        }
+        publish(&manager, "1.0.0", "a.img", "stable").await;
+        publish(&manager, "1.1.0", "b.img", "stable").await;
+
+        // The target has to be on the channel rolled back
+        assert!(
+            manager
+                .rollback("1.0.0", Some("beta"), false, "ops", "boot loop")
+                .await
+                .is_err()
+        );
+
+        let rolled_back = manager
+            .rollback("1.0.0", Some("stable"), false, "ops", "boot loop")
+            .await
+            .unwrap();
+        assert_eq!(
+            rolled_back,
+            vec![("stable".to_string(), "1.1.0".to_string())]
+        );
+        let history = manager.list_versions().await.unwrap();
+        assert_eq!(history.channel_head("stable").unwrap().version, "1.0.0");
+        let bad = history.find("1.1.0").unwrap();
+        assert!(bad.channels.is_empty());
+        assert_eq!(bad.rolled_back_on("stable").unwrap().by, "ops");
+
+        // A fixed release takes over, and withdrawing it does not bring back
+        // the release rolled back from
+        publish(&manager, "1.2.0", "c.img", "stable").await;
+        let history = manager.list_versions().await.unwrap();
+        assert_eq!(history.channel_head("stable").unwrap().version, "1.2.0");
+        manager
+            .set_status("1.2.0", ReleaseStatus::Yanked, false)
+            .await
+            .unwrap();
+        let history = manager.list_versions().await.unwrap();
+        assert_eq!(history.channel_head("stable").unwrap().version, "1.0.0");
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 }
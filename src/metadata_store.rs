@@ -0,0 +1,355 @@
+use crate::atomic_file::write_atomic;
+use crate::config::ServerConfig;
+use crate::metadata::{DEFAULT_CHANNEL, VersionHistory};
+use crate::repair::describe_parse_error;
+use crate::rollout::RolloutState;
+use crate::sqlite_store::SqliteStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+
+// Where the version history and rollout state are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    // version-history.json, rollouts.json and latest.json in metadata_dir
+    #[default]
+    Json,
+    // A single SQLite database, metadata_dir/metadata.db by default
+    Sqlite,
+}
+
+impl std::fmt::Display for StoreBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreBackend::Json => write!(f, "json"),
+            StoreBackend::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+// Persistence for release metadata. Callers serialize writers with the
+// metadata lock; a store only has to make each save all-or-nothing.
+#[async_trait]
+pub trait MetadataStore: Send + Sync {
+    fn backend(&self) -> StoreBackend;
+
+    // A store that has never been written to holds an empty history
+    async fn load_history(&self) -> Result<VersionHistory>;
+
+    // Releases without rollout state are fully rolled out
+    async fn load_rollouts(&self) -> Result<RolloutState>;
+
+    // Persist whichever parts changed
+    async fn save(
+        &self,
+        history: Option<&VersionHistory>,
+        rollouts: Option<&RolloutState>,
+    ) -> Result<()>;
+}
+
+pub fn open_store(config: &ServerConfig) -> Arc<dyn MetadataStore> {
+    open_backend(config, config.storage.backend)
+}
+
+// A specific backend regardless of the configured one, e.g. to migrate between them
+pub fn open_backend(config: &ServerConfig, backend: StoreBackend) -> Arc<dyn MetadataStore> {
+    let metadata_dir = PathBuf::from(&config.paths.metadata_dir);
+    match backend {
+        StoreBackend::Json => Arc::new(JsonStore::new(metadata_dir)),
+        StoreBackend::Sqlite => {
+            let path = config
+                .storage
+                .sqlite_path
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| metadata_dir.join("metadata.db"));
+            Arc::new(SqliteStore::new(path))
+        }
+    }
+}
+
+// The original file layout in metadata_dir
+pub struct JsonStore {
+    metadata_dir: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new<P: AsRef<Path>>(metadata_dir: P) -> Self {
+        Self {
+            metadata_dir: metadata_dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataStore for JsonStore {
+    fn backend(&self) -> StoreBackend {
+        StoreBackend::Json
+    }
+
+    async fn load_history(&self) -> Result<VersionHistory> {
+        let history_path = self.metadata_dir.join("version-history.json");
+        let content = match fs::read_to_string(&history_path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(VersionHistory::empty());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Never treat a corrupt history as empty: the next write would erase
+        // every release. Refuse until it has been repaired.
+        serde_json::from_str::<VersionHistory>(&content).map_err(|e| {
+            anyhow::anyhow!(
+                "{}. Run `repair` to rebuild it.",
+                describe_parse_error(&history_path, &e)
+            )
+        })
+    }
+
+    async fn load_rollouts(&self) -> Result<RolloutState> {
+        let rollouts_path = self.metadata_dir.join("rollouts.json");
+        match fs::read_to_string(&rollouts_path).await {
+            Ok(content) => serde_json::from_str::<RolloutState>(&content)
+                .map_err(|e| anyhow::anyhow!(describe_parse_error(&rollouts_path, &e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RolloutState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(
+        &self,
+        history: Option<&VersionHistory>,
+        rollouts: Option<&RolloutState>,
+    ) -> Result<()> {
+        fs::create_dir_all(&self.metadata_dir).await?;
+
+        // Rollout state first, so a new release is staged before devices can see it
+        if let Some(rollouts) = rollouts {
+            let content = serde_json::to_string_pretty(rollouts)?;
+            write_atomic(&self.metadata_dir.join("rollouts.json"), content.as_bytes()).await?;
+        }
+
+        let Some(history) = history else {
+            return Ok(());
+        };
+        let content = serde_json::to_string_pretty(history)?;
+        write_atomic(
+            &self.metadata_dir.join("version-history.json"),
+            content.as_bytes(),
+        )
+        .await?;
+
+        // latest.json is derived from the history. It is rewritten whenever it
+        // disagrees, which also repairs a crash between the two writes. Without
+        // a stable head it goes, so a removed or yanked release is never offered
+        // through it again.
+        let latest_path = self.metadata_dir.join("latest.json");
+        match history.channel_head(DEFAULT_CHANNEL) {
+            Some(head) => {
+                let latest = serde_json::to_string_pretty(head)?;
+                let current = fs::read_to_string(&latest_path).await.unwrap_or_default();
+                if current != latest {
+                    write_atomic(&latest_path, latest.as_bytes()).await?;
+                }
+            }
+            None => match fs::remove_file(&latest_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compatibility::Compatibility;
+    use crate::metadata::KernelInfo;
+    use crate::rollout::{FailureBudget, RolloutPause};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("metadata-store-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn sample_metadata() -> (VersionHistory, RolloutState) {
+        let mut history = VersionHistory::empty();
+        for (version, channel) in [
+            ("1.0.0", "stable"),
+            ("1.1.0", "stable"),
+            ("2.0.0-rc.1", "beta"),
+        ] {
+            let mut kernel = KernelInfo::new(
+                version.to_string(),
+                format!("kernel-v{}.img", version),
+                1024,
+                format!("sha256:{:0>64}", version.len()),
+                format!("Release {}", version),
+            );
+            kernel.channels = vec![channel.to_string()];
+            history.versions.push(kernel);
+        }
+        history.versions[1].compatibility = Some(Compatibility {
+            boards: vec!["rev-b*".to_string()],
+            ..Default::default()
+        });
+        history
+            .pinned
+            .insert("stable".to_string(), "1.0.0".to_string());
+        history.recompute_heads();
+
+        let mut rollouts = RolloutState::default();
+        rollouts.set_percentage("1.1.0", 25);
+        rollouts.set_failure_budget(
+            "1.1.0",
+            FailureBudget {
+                max_failure_percent: 5.0,
+                ..Default::default()
+            },
+        );
+        rollouts.pause(
+            "1.1.0",
+            RolloutPause {
+                paused_at: chrono::Utc::now(),
+                reason: "3 of 10 installs failed".to_string(),
+                attempts: 10,
+                failures: 3,
+            },
+        );
+        (history, rollouts)
+    }
+
+    fn as_json<T: Serialize>(value: &T) -> String {
+        serde_json::to_string_pretty(value).unwrap()
+    }
+
+    // Behaviour every backend has to provide
+    async fn exercise_store(store: &dyn MetadataStore) {
+        assert!(store.load_history().await.unwrap().versions.is_empty());
+        assert_eq!(
+            as_json(&store.load_rollouts().await.unwrap()),
+            as_json(&RolloutState::default())
+        );
+
+        let (mut history, rollouts) = sample_metadata();
+        store.save(Some(&history), Some(&rollouts)).await.unwrap();
+        assert_eq!(
+            as_json(&store.load_history().await.unwrap()),
+            as_json(&history)
+        );
+        assert_eq!(
+            as_json(&store.load_rollouts().await.unwrap()),
+            as_json(&rollouts)
+        );
+
+        // Saving one part leaves the other alone
+        history.versions.remove(0);
+        history.pinned.clear();
+        history.recompute_heads();
+        store.save(Some(&history), None).await.unwrap();
+        assert_eq!(
+            as_json(&store.load_history().await.unwrap()),
+            as_json(&history)
+        );
+        assert_eq!(
+            as_json(&store.load_rollouts().await.unwrap()),
+            as_json(&rollouts)
+        );
+
+        store
+            .save(None, Some(&RolloutState::default()))
+            .await
+            .unwrap();
+        assert_eq!(
+            as_json(&store.load_history().await.unwrap()),
+            as_json(&history)
+        );
+        assert!(
+            store
+                .load_rollouts()
+                .await
+                .unwrap()
+                .paused("1.1.0")
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_store() {
+        let dir = temp_dir();
+        exercise_store(&JsonStore::new(&dir)).await;
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_latest_json_follows_stable_head() {
+        let dir = temp_dir();
+        let store = JsonStore::new(&dir);
+        let latest_path = dir.join("latest.json");
+        let (mut history, _) = sample_metadata();
+        store.save(Some(&history), None).await.unwrap();
+        let latest: KernelInfo =
+            serde_json::from_str(&fs::read_to_string(&latest_path).await.unwrap()).unwrap();
+        assert_eq!(latest.version, "1.0.0");
+
+        // Once stable has no release left, latest.json no longer names one
+        history
+            .versions
+            .retain(|kernel| kernel.channels != ["stable"]);
+        history.recompute_heads();
+        store.save(Some(&history), None).await.unwrap();
+        assert!(!latest_path.exists());
+        store.save(Some(&history), None).await.unwrap();
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let dir = temp_dir();
+        exercise_store(&SqliteStore::new(dir.join("metadata.db"))).await;
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_between_backends() {
+        let dir = temp_dir();
+        let json = JsonStore::new(&dir);
+        let sqlite = SqliteStore::new(dir.join("metadata.db"));
+        let (history, rollouts) = sample_metadata();
+        json.save(Some(&history), Some(&rollouts)).await.unwrap();
+
+        sqlite
+            .save(
+                Some(&json.load_history().await.unwrap()),
+                Some(&json.load_rollouts().await.unwrap()),
+            )
+            .await
+            .unwrap();
+        let back = JsonStore::new(dir.join("back"));
+        back.save(
+            Some(&sqlite.load_history().await.unwrap()),
+            Some(&sqlite.load_rollouts().await.unwrap()),
+        )
+        .await
+        .unwrap();
+
+        for file in ["version-history.json", "rollouts.json", "latest.json"] {
+            assert_eq!(
+                fs::read_to_string(dir.join(file)).await.unwrap(),
+                fs::read_to_string(dir.join("back").join(file))
+                    .await
+                    .unwrap(),
+                "{} differs after a round trip",
+                file
+            );
+        }
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use std::time::SystemTime;
+
+// Inclusive byte range within a file, as used by `Content-Range`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    // No usable Range header: serve the whole file with 200
+    Full,
+    // A single satisfiable range: serve it with 206
+    Partial(ByteRange),
+    // More than one range was requested; multipart replies are not supported
+    MultipleRanges,
+    // The range lies outside the file: reply with 416
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, file_size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, file_size)
+    }
+}
+
+// Parse a `Range` header against a file of `file_size` bytes.
+// Syntactically invalid headers are ignored and the full file is served (RFC 9110 14.2).
+pub fn parse_range(header: &str, file_size: u64) -> RangeRequest {
+    let header = header.trim();
+    let Some(spec) = header.strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let parts: Vec<&str> = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect();
+
+    match parts.as_slice() {
+        [] => RangeRequest::Full,
+        [single] => parse_single(single, file_size),
+        _ => RangeRequest::MultipleRanges,
+    }
+}
+
+fn parse_single(spec: &str, file_size: u64) -> RangeRequest {
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last N bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        if suffix == 0 || file_size == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        let start = file_size.saturating_sub(suffix);
+        return RangeRequest::Partial(ByteRange {
+            start,
+            end: file_size - 1,
+        });
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+    let end = if end.is_empty() {
+        None
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= file_size {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    let last = file_size - 1;
+    RangeRequest::Partial(ByteRange {
+        start,
+        end: end.map_or(last, |end| end.min(last)),
+    })
+}
+
+// Evaluate an `If-Range` precondition. The range is only honored when the
+// validator still matches the current representation.
+pub fn if_range_matches(if_range: &str, etag: &str, last_modified: &str) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        // Weak validators never match for If-Range
+        return false;
+    }
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    if_range == last_modified
+}
+
+// Format a timestamp as an HTTP-date (IMF-fixdate)
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let range = |start, end| RangeRequest::Partial(ByteRange { start, end });
+
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), range(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=900-5000", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), range(0, 999));
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=0-1,5-9", 1000),
+            RangeRequest::MultipleRanges
+        );
+        assert_eq!(parse_range("bytes=9-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let etag = "\"abc\"";
+        let date = "Sun, 22 Jun 2025 05:48:52 GMT";
+        assert!(if_range_matches("\"abc\"", etag, date));
+        assert!(!if_range_matches("\"def\"", etag, date));
+        assert!(!if_range_matches("W/\"abc\"", etag, date));
+        assert!(if_range_matches(date, etag, date));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::checksum::calculate_file_checksum;
+use crate::metadata::{KernelInfo, VersionHistory};
+use crate::versioning::{compare_versions, parse_version};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+
+// Where a release in a rebuilt history came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoverySource {
+    History,
+    LatestJson,
+    KernelsDir,
+}
+
+#[derive(Debug)]
+pub struct RepairPlan {
+    // Parse error of the existing history, if it is corrupt
+    pub corruption: Option<String>,
+    pub history: VersionHistory,
+    pub sources: BTreeMap<String, RecoverySource>,
+    pub warnings: Vec<String>,
+}
+
+// Parse error of a metadata file; serde's message ends with the line and column
+pub fn describe_parse_error(path: &Path, error: &serde_json::Error) -> String {
+    format!("{} is corrupt: {}", path.display(), error)
+}
+
+// Rebuild the version history from whatever survives: complete release entries
+// in the (possibly truncated) history, latest.json, and, only if the history is
+// corrupt, the images in kernels_dir.
+pub async fn plan_repair(kernels_dir: &Path, metadata_dir: &Path) -> Result<RepairPlan> {
+    let history_path = metadata_dir.join("version-history.json");
+    let mut plan = RepairPlan {
+        corruption: None,
+        history: VersionHistory::empty(),
+        sources: BTreeMap::new(),
+        warnings: Vec::new(),
+    };
+
+    let mut releases: BTreeMap<String, KernelInfo> = BTreeMap::new();
+    match fs::read_to_string(&history_path).await {
+        Ok(content) => match serde_json::from_str::<VersionHistory>(&content) {
+            Ok(history) => {
+                // Nothing to salvage; keep pins and channels exactly as they are
+                plan.history = history;
+                for kernel in &plan.history.versions {
+                    plan.sources
+                        .insert(kernel.version.clone(), RecoverySource::History);
+                }
+            }
+            Err(e) => {
+                plan.corruption = Some(describe_parse_error(&history_path, &e));
+                for kernel in salvage_releases(&content) {
+                    plan.sources
+                        .insert(kernel.version.clone(), RecoverySource::History);
+                    releases.insert(kernel.version.clone(), kernel);
+                }
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            plan.warnings
+                .push(format!("{} does not exist", history_path.display()));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let latest_path = metadata_dir.join("latest.json");
+    if let Ok(content) = fs::read_to_string(&latest_path).await {
+        match serde_json::from_str::<KernelInfo>(&content) {
+            Ok(kernel) => {
+                let known = releases.contains_key(&kernel.version)
+                    || plan.history.find(&kernel.version).is_some();
+                if !known {
+                    plan.sources
+                        .insert(kernel.version.clone(), RecoverySource::LatestJson);
+                    releases.insert(kernel.version.clone(), kernel);
+                }
+            }
+            Err(e) => plan.warnings.push(describe_parse_error(&latest_path, &e)),
+        }
+    }
+
+    plan.history.versions.extend(releases.into_values());
+    verify_images(kernels_dir, &mut plan).await;
+    // An intact history says exactly what is published; files next to it are
+    // not releases just because they are there
+    if plan.corruption.is_some() {
+        rescan_kernels_dir(kernels_dir, &mut plan).await?;
+    }
+
+    plan.history
+        .versions
+        .sort_by(|a, b| compare_versions(&a.version, &b.version));
+    plan.history.recompute_heads();
+    Ok(plan)
+}
+
+// Check recovered releases against their images in kernels_dir
+async fn verify_images(kernels_dir: &Path, plan: &mut RepairPlan) {
+    for kernel in &plan.history.versions {
+        let path = kernels_dir.join(&kernel.kernel_file);
+        match calculate_file_checksum(&path).await {
+            Ok(checksum) if checksum == kernel.checksum => {}
+            Ok(_) => plan.warnings.push(format!(
+                "Version {}: {} no longer matches its recorded checksum",
+                kernel.version, kernel.kernel_file
+            )),
+            Err(_) => plan.warnings.push(format!(
+                "Version {}: {} is missing",
+                kernel.version, kernel.kernel_file
+            )),
+        }
+    }
+}
+
+// Add images no release mentions, taking the version from file names like
+// `kernel-v1.2.3.img`. They are not published to any channel: whatever was
+// lying around is only offered to devices once it is promoted.
+async fn rescan_kernels_dir(kernels_dir: &Path, plan: &mut RepairPlan) -> Result<()> {
+    let mut entries = match fs::read_dir(kernels_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with('.') || !entry.file_type().await?.is_file() {
+            continue;
+        }
+        if plan
+            .history
+            .versions
+            .iter()
+            .any(|kernel| kernel.kernel_file == file_name)
+        {
+            continue;
+        }
+        found.push(file_name);
+    }
+    found.sort();
+
+    for file_name in found {
+        let Some(version) = version_from_file_name(&file_name) else {
+            plan.warnings.push(format!(
+                "{}: no version in file name, not recovered",
+                file_name
+            ));
+            continue;
+        };
+        if plan.history.find(&version).is_some() {
+            plan.warnings.push(format!(
+                "{}: version {} already recovered from metadata",
+                file_name, version
+            ));
+            continue;
+        }
+
+        let path = kernels_dir.join(&file_name);
+        let file_size = fs::metadata(&path).await?.len();
+        let checksum = calculate_file_checksum(&path).await?;
+        let mut kernel = KernelInfo::new(
+            version.clone(),
+            file_name,
+            file_size,
+            checksum,
+            "Recovered by repair".to_string(),
+        );
+        kernel.channels = Vec::new();
+        plan.sources
+            .insert(version.clone(), RecoverySource::KernelsDir);
+        plan.history.versions.push(kernel);
+    }
+    Ok(())
+}
+
+// Every complete release entry in a damaged history file. Starts a parse at
+// each '{' and keeps whatever deserializes as a release.
+pub fn salvage_releases(content: &str) -> Vec<KernelInfo> {
+    let mut releases: Vec<KernelInfo> = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = content[offset..].find('{').map(|i| offset + i) {
+        let mut stream =
+            serde_json::Deserializer::from_str(&content[start..]).into_iter::<KernelInfo>();
+        match stream.next() {
+            Some(Ok(kernel)) => {
+                releases.retain(|existing| existing.version != kernel.version);
+                releases.push(kernel);
+                offset = start + stream.byte_offset();
+            }
+            _ => offset = start + 1,
+        }
+    }
+    releases
+}
+
+fn version_from_file_name(file_name: &str) -> Option<String> {
+    let stem = file_name
+        .strip_suffix(".img")
+        .or_else(|| file_name.rsplit_once('.').map(|(stem, _)| stem))
+        .unwrap_or(file_name);
+    let candidate = stem.rsplit_once("-v").map_or(stem, |(_, version)| version);
+    parse_version(candidate).ok().map(|_| candidate.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: &str = r#"{
+  "versions": [
+    {
+      "version": "1.0.0",
+      "kernel_file": "kernel-v1.0.0.img",
+      "file_size": 21,
+      "checksum": "sha256:00",
+      "release_date": "2025-06-15T05:14:20.937279Z",
+      "description": "first",
+      "download_url": "/kernels/kernel-v1.0.0.img",
+      "compatibility": { "boards": ["rev-b*"] }
+    },
+    {
+      "version": "2.0.0",
+      "kernel_file": "kernel-v2.0.0.img",
+      "file_size": 40,
+      "checksum": "sha256:01",
+      "release_date": "2025-06-22T05:48:52.731930Z",
+      "description": "second",
+      "download_url": "/kernels/kernel-v2.0.0.img"
+    }
+  ],
+  "latest": "2.0.0"
+}"#;
+
+    #[test]
+    fn test_salvage_truncated_history() {
+        let truncated = &HISTORY[..HISTORY.find("\"description\": \"second\"").unwrap()];
+        assert!(serde_json::from_str::<VersionHistory>(truncated).is_err());
+
+        let releases = salvage_releases(truncated);
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].version, "1.0.0");
+        assert!(releases[0].compatibility.is_some());
+
+        assert_eq!(salvage_releases(HISTORY).len(), 2);
+        assert!(salvage_releases("\u{0}garbage{{").is_empty());
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let error = serde_json::from_str::<VersionHistory>("{\n  \"versions\": [,\n").unwrap_err();
+        let message = describe_parse_error(Path::new("version-history.json"), &error);
+        assert!(message.starts_with("version-history.json is corrupt: "));
+        assert!(message.contains("at line 2 column"));
+    }
+
+    #[test]
+    fn test_version_from_file_name() {
+        assert_eq!(
+            version_from_file_name("kernel-v1.0.2.img").as_deref(),
+            Some("1.0.2")
+        );
+        assert_eq!(
+            version_from_file_name("kernel-v2.0.0-rc.1.img").as_deref(),
+            Some("2.0.0-rc.1")
+        );
+        assert_eq!(version_from_file_name("vmlinuz"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rescan_only_corrupt_history() {
+        let dir = std::env::temp_dir().join(format!("repair-{}", uuid::Uuid::new_v4()));
+        let kernels_dir = dir.join("kernels");
+        let metadata_dir = dir.join("metadata");
+        fs::create_dir_all(&kernels_dir).await.unwrap();
+        fs::create_dir_all(&metadata_dir).await.unwrap();
+        fs::write(kernels_dir.join("kernel-v3.0.0.img"), b"stray")
+            .await
+            .unwrap();
+
+        fs::write(metadata_dir.join("version-history.json"), HISTORY)
+            .await
+            .unwrap();
+        let plan = plan_repair(&kernels_dir, &metadata_dir).await.unwrap();
+        assert!(plan.corruption.is_none());
+        assert!(plan.history.find("3.0.0").is_none());
+
+        let truncated = &HISTORY[..HISTORY.find("\"description\": \"second\"").unwrap()];
+        fs::write(metadata_dir.join("version-history.json"), truncated)
+            .await
+            .unwrap();
+        let plan = plan_repair(&kernels_dir, &metadata_dir).await.unwrap();
+        assert!(plan.corruption.is_some());
+        let recovered = plan.history.find("3.0.0").expect("image recovered");
+        assert!(recovered.channels.is_empty());
+        assert_eq!(plan.sources["3.0.0"], RecoverySource::KernelsDir);
+        // Recovered images are never offered until promoted
+        assert_eq!(
+            plan.history
+                .channel_head("stable")
+                .map(|kernel| kernel.version.as_str()),
+            Some("1.0.0")
+        );
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use crate::device_registry::{DeviceEvent, InstallStatus, release_stats};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Buckets per release; percentages map onto them in steps of 0.01%
+const BUCKETS: u64 = 10_000;
+
+// Staged rollout state, persisted as rollouts.json next to version-history.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RolloutState {
+    #[serde(default)]
+    pub releases: BTreeMap<String, ReleaseRollout>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseRollout {
+    pub percentage: u8,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_budget: Option<FailureBudget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<RolloutPause>,
+    // Failures reported before the last resume do not count against the budget
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_at: Option<DateTime<Utc>>,
+}
+
+// Share of finished install attempts allowed to fail or roll back within a
+// sliding window before the release is paused
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FailureBudget {
+    pub max_failure_percent: f64,
+    pub window_minutes: u32,
+    // Too few attempts say nothing about a release; don't pause on them
+    pub min_attempts: u64,
+}
+
+// Why and when a release stopped being offered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutPause {
+    pub paused_at: DateTime<Utc>,
+    pub reason: String,
+    pub attempts: u64,
+    pub failures: u64,
+}
+
+impl Default for FailureBudget {
+    fn default() -> Self {
+        Self {
+            max_failure_percent: 2.0,
+            window_minutes: 60,
+            min_attempts: 20,
+        }
+    }
+}
+
+impl ReleaseRollout {
+    fn new() -> Self {
+        Self {
+            percentage: 100,
+            updated_at: Utc::now(),
+            failure_budget: None,
+            paused: None,
+            resumed_at: None,
+        }
+    }
+}
+
+impl RolloutState {
+    // Releases without rollout state are fully rolled out
+    pub fn percentage(&self, version: &str) -> u8 {
+        self.releases
+            .get(version)
+            .map_or(100, |rollout| rollout.percentage)
+    }
+
+    pub fn set_percentage(&mut self, version: &str, percentage: u8) {
+        let rollout = self.entry(version);
+        rollout.percentage = percentage.min(100);
+        rollout.updated_at = Utc::now();
+    }
+
+    // Releases without their own budget use the default one
+    pub fn failure_budget(&self, version: &str) -> FailureBudget {
+        self.releases
+            .get(version)
+            .and_then(|rollout| rollout.failure_budget)
+            .unwrap_or_default()
+    }
+
+    pub fn set_failure_budget(&mut self, version: &str, budget: FailureBudget) {
+        let rollout = self.entry(version);
+        rollout.failure_budget = Some(budget);
+        rollout.updated_at = Utc::now();
+    }
+
+    pub fn paused(&self, version: &str) -> Option<&RolloutPause> {
+        self.releases
+            .get(version)
+            .and_then(|rollout| rollout.paused.as_ref())
+    }
+
+    pub fn pause(&mut self, version: &str, pause: RolloutPause) {
+        let rollout = self.entry(version);
+        rollout.updated_at = pause.paused_at;
+        rollout.paused = Some(pause);
+    }
+
+    // Returns false if the release was not paused
+    pub fn resume(&mut self, version: &str) -> bool {
+        let Some(rollout) = self.releases.get_mut(version) else {
+            return false;
+        };
+        if rollout.paused.take().is_none() {
+            return false;
+        }
+        rollout.resumed_at = Some(Utc::now());
+        rollout.updated_at = Utc::now();
+        true
+    }
+
+    // Check a release's failure budget against reported install outcomes.
+    // Returns the pause to record if the budget is exceeded.
+    pub fn evaluate_failure_budget(
+        &self,
+        version: &str,
+        events: &[DeviceEvent],
+        now: DateTime<Utc>,
+    ) -> Option<RolloutPause> {
+        if self.paused(version).is_some() {
+            return None;
+        }
+        let budget = self.failure_budget(version);
+        let mut since = now - Duration::minutes(i64::from(budget.window_minutes));
+        if let Some(resumed_at) = self.releases.get(version).and_then(|r| r.resumed_at) {
+            since = since.max(resumed_at);
+        }
+
+        // Only authenticated reports count, each device with the outcome of its
+        // latest finished attempt, so repeated reports cannot pause a release
+        let recent: Vec<DeviceEvent> = events
+            .iter()
+            .filter(|event| {
+                event.authenticated
+                    && event.version == version
+                    && event.timestamp >= since
+                    && matches!(
+                        event.status,
+                        InstallStatus::BootedOk | InstallStatus::RolledBack | InstallStatus::Failed
+                    )
+            })
+            .cloned()
+            .collect();
+        let stats = release_stats(&recent).remove(version)?;
+
+        let failures = stats.failed + stats.rolled_back;
+        let attempts = failures + stats.booted_ok;
+        if attempts == 0 || attempts < budget.min_attempts {
+            return None;
+        }
+        let failure_percent = failures as f64 / attempts as f64 * 100.0;
+        if failure_percent <= budget.max_failure_percent {
+            return None;
+        }
+
+        Some(RolloutPause {
+            paused_at: now,
+            reason: format!(
+                "{} of {} install attempts ({:.1}%) failed or rolled back within {} minutes, above the {}% budget",
+                failures,
+                attempts,
+                failure_percent,
+                budget.window_minutes,
+                budget.max_failure_percent
+            ),
+            attempts,
+            failures,
+        })
+    }
+
+    fn entry(&mut self, version: &str) -> &mut ReleaseRollout {
+        self.releases
+            .entry(version.to_string())
+            .or_insert_with(ReleaseRollout::new)
+    }
+
+    // Whether a release is offered to a device. Paused releases are offered to
+    // nobody, and anonymous requests only see releases that are rolled out to
+    // the whole fleet.
+    pub fn includes(&self, version: &str, device_id: Option<&str>) -> bool {
+        if self.paused(version).is_some() {
+            return false;
+        }
+        let percentage = self.percentage(version);
+        if percentage >= 100 {
+            return true;
+        }
+        match device_id {
+            Some(device_id) => device_bucket(device_id, version) < u64::from(percentage) * 100,
+            None => false,
+        }
+    }
+}
+
+// Deterministic bucket in 0..10000 for a device and release. A device keeps its
+// bucket while the percentage grows, so it never flaps between versions.
+pub fn device_bucket(device_id: &str, version: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(version.as_bytes())
+        .chain_update([0u8])
+        .chain_update(device_id.as_bytes())
+        .finalize();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) % BUCKETS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollout_is_monotonic() {
+        let mut state = RolloutState::default();
+        let devices: Vec<String> = (0..2000).map(|i| format!("device-{}", i)).collect();
+
+        let mut previous: Vec<&String> = Vec::new();
+        for percentage in [5, 25, 50, 100] {
+            state.set_percentage("2.0.0", percentage);
+            let included: Vec<&String> = devices
+                .iter()
+                .filter(|d| state.includes("2.0.0", Some(d)))
+                .collect();
+            assert!(previous.iter().all(|d| included.contains(d)));
+
+            let share = included.len() as f64 / devices.len() as f64 * 100.0;
+            assert!((share - f64::from(percentage)).abs() < 3.0);
+            previous = included;
+        }
+    }
+
+    #[test]
+    fn test_anonymous_requests() {
+        let mut state = RolloutState::default();
+        assert!(state.includes("1.0.0", None));
+        state.set_percentage("1.0.0", 50);
+        assert!(!state.includes("1.0.0", None));
+    }
+
+    #[test]
+    fn test_failure_budget() {
+        let now = Utc::now();
+        let event = |device: usize, status: InstallStatus, minutes_ago: i64| DeviceEvent {
+            device_id: format!("device-{}", device),
+            version: "2.0.0".to_string(),
+            status,
+            error_code: None,
+            message: None,
+            timestamp: now - Duration::minutes(minutes_ago),
+            authenticated: true,
+        };
+
+        let mut state = RolloutState::default();
+        let mut events: Vec<DeviceEvent> = (0..49)
+            .map(|i| event(i, InstallStatus::BootedOk, 5))
+            .collect();
+        events.push(event(49, InstallStatus::Failed, 5));
+        // 1 of 50 is within the default 2% budget
+        assert!(
+            state
+                .evaluate_failure_budget("2.0.0", &events, now)
+                .is_none()
+        );
+
+        // Failures outside the window don't count
+        events.push(event(50, InstallStatus::RolledBack, 90));
+        assert!(
+            state
+                .evaluate_failure_budget("2.0.0", &events, now)
+                .is_none()
+        );
+
+        // Nor do unauthenticated reports, or repeated reports from one device,
+        // or a retry hiding an earlier failure
+        for _ in 0..10 {
+            events.push(DeviceEvent {
+                authenticated: false,
+                ..event(52, InstallStatus::Failed, 1)
+            });
+            events.push(event(49, InstallStatus::Failed, 1));
+        }
+        events.push(event(49, InstallStatus::Downloading, 1));
+        assert!(
+            state
+                .evaluate_failure_budget("2.0.0", &events, now)
+                .is_none()
+        );
+
+        events.push(event(51, InstallStatus::RolledBack, 1));
+        let pause = state
+            .evaluate_failure_budget("2.0.0", &events, now)
+            .expect("budget exceeded");
+        assert_eq!((pause.failures, pause.attempts), (2, 51));
+
+        state.pause("2.0.0", pause);
+        assert!(!state.includes("2.0.0", Some("device-1")));
+        assert!(state.resume("2.0.0"));
+        assert!(!state.resume("2.0.0"));
+        assert!(state.includes("2.0.0", Some("device-1")));
+        // Only failures after the resume count again
+        assert!(
+            state
+                .evaluate_failure_budget("2.0.0", &events, Utc::now())
+                .is_none()
+        );
+    }
+}
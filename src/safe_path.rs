@@ -0,0 +1,274 @@
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+// Names left behind by editors, partial downloads and interrupted copies
+const TEMPORARY_SUFFIXES: &[&str] = &[".tmp", ".temp", ".part", ".partial", ".swp", "~"];
+
+// What to do with a requested file that is a symlink
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    // Never serve symlinks
+    Deny,
+    // Serve symlinks whose target is inside the directory
+    #[default]
+    Within,
+    // Serve symlinks wherever they point
+    Follow,
+}
+
+// Why a requested file is not served
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathRejection {
+    // Not a single plain file name, e.g. "../x", "a/b" or an encoded "%2e%2e"
+    Invalid,
+    Hidden,
+    Temporary,
+    Symlink,
+    // Resolves to a file outside the directory
+    Escapes,
+    NotAFile,
+    NotFound,
+    // No release references it
+    Unlisted,
+}
+
+impl std::fmt::Display for PathRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathRejection::Invalid => write!(f, "not a plain file name"),
+            PathRejection::Hidden => write!(f, "hidden file"),
+            PathRejection::Temporary => write!(f, "temporary file"),
+            PathRejection::Symlink => write!(f, "symlinks are not served"),
+            PathRejection::Escapes => write!(f, "resolves outside the directory"),
+            PathRejection::NotAFile => write!(f, "not a regular file"),
+            PathRejection::NotFound => write!(f, "not found"),
+            PathRejection::Unlisted => write!(f, "not referenced by any release"),
+        }
+    }
+}
+
+// The file name a request names, percent-decoded. Only a single, plain,
+// visible and non-temporary name is accepted.
+pub fn decode_file_name(requested: &str) -> Result<String, PathRejection> {
+    let name = percent_decode_str(requested)
+        .decode_utf8()
+        .map_err(|_| PathRejection::Invalid)?;
+
+    let mut components = Path::new(name.as_ref()).components();
+    let single_component = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(component)), None) if component == name.as_ref()
+    );
+    if !single_component || name.chars().any(|c| c == '\\' || c.is_control()) {
+        return Err(PathRejection::Invalid);
+    }
+    if name.starts_with('.') {
+        return Err(PathRejection::Hidden);
+    }
+    if TEMPORARY_SUFFIXES
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+    {
+        return Err(PathRejection::Temporary);
+    }
+    Ok(name.into_owned())
+}
+
+// A file in `root` that passed every check, opened for serving
+#[derive(Debug)]
+pub struct ResolvedFile {
+    // Canonical path of the file
+    pub path: PathBuf,
+    pub file: fs::File,
+    pub metadata: std::fs::Metadata,
+}
+
+// Open a file in `root`, once it is certain to be a regular file that (after
+// following an allowed symlink) lives inside `root`. The checks are made on the
+// canonical path and the open handle must be the very file that was checked, so
+// swapping in a symlink between the two is caught rather than followed.
+pub async fn resolve_in(
+    root: &Path,
+    name: &str,
+    symlinks: SymlinkPolicy,
+) -> Result<ResolvedFile, PathRejection> {
+    let path = root.join(name);
+    let link_metadata = fs::symlink_metadata(&path)
+        .await
+        .map_err(|_| PathRejection::NotFound)?;
+    if link_metadata.file_type().is_symlink() && symlinks == SymlinkPolicy::Deny {
+        return Err(PathRejection::Symlink);
+    }
+
+    let resolved = fs::canonicalize(&path)
+        .await
+        .map_err(|_| PathRejection::NotFound)?;
+    let inside = match fs::canonicalize(root).await {
+        Ok(root) => resolved.starts_with(&root),
+        Err(_) => false,
+    };
+    if !inside && symlinks != SymlinkPolicy::Follow {
+        return Err(PathRejection::Escapes);
+    }
+
+    // A canonical path never ends in a symlink, unless one was swapped in since
+    let checked = fs::symlink_metadata(&resolved)
+        .await
+        .map_err(|_| PathRejection::NotFound)?;
+    if checked.file_type().is_symlink() {
+        return Err(PathRejection::Symlink);
+    }
+    if !checked.is_file() {
+        return Err(PathRejection::NotAFile);
+    }
+
+    let file = fs::File::open(&resolved)
+        .await
+        .map_err(|_| PathRejection::NotFound)?;
+    let metadata = file.metadata().await.map_err(|_| PathRejection::NotFound)?;
+    if !metadata.is_file() || !same_file(&checked, &metadata) {
+        return Err(PathRejection::Symlink);
+    }
+    Ok(ResolvedFile {
+        path: resolved,
+        file,
+        metadata,
+    })
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Requests that must never reach the file system as given
+    #[test]
+    fn test_hostile_names() {
+        let cases = [
+            ("", PathRejection::Invalid),
+            ("..", PathRejection::Invalid),
+            (".", PathRejection::Invalid),
+            ("../etc/passwd", PathRejection::Invalid),
+            ("..%2fetc%2fpasswd", PathRejection::Invalid),
+            ("%2e%2e", PathRejection::Invalid),
+            ("%2E%2E", PathRejection::Invalid),
+            ("%2e%2e%2f%2e%2e%2fetc%2fpasswd", PathRejection::Invalid),
+            ("%2fetc%2fpasswd", PathRejection::Invalid),
+            ("/etc/passwd", PathRejection::Invalid),
+            ("sub/kernel.img", PathRejection::Invalid),
+            ("sub%2fkernel.img", PathRejection::Invalid),
+            ("..\\..\\windows", PathRejection::Invalid),
+            ("..%5c..%5cwindows", PathRejection::Invalid),
+            ("kernel.img%00.txt", PathRejection::Invalid),
+            ("kernel%0a.img", PathRejection::Invalid),
+            ("%c0%ae%c0%ae", PathRejection::Invalid),
+            (".hidden", PathRejection::Hidden),
+            ("%2ehidden", PathRejection::Hidden),
+            (".kernel-v1.0.0.img.tmp-1234", PathRejection::Hidden),
+            ("kernel-v1.0.0.img.tmp", PathRejection::Temporary),
+            ("kernel-v1.0.0.img.part", PathRejection::Temporary),
+            ("kernel-v1.0.0.img~", PathRejection::Temporary),
+            ("kernel-v1.0.0.img.swp", PathRejection::Temporary),
+        ];
+        for (requested, expected) in cases {
+            assert_eq!(
+                decode_file_name(requested),
+                Err(expected),
+                "{:?} was not rejected",
+                requested
+            );
+        }
+
+        assert_eq!(
+            decode_file_name("kernel-v1.0.0.img").unwrap(),
+            "kernel-v1.0.0.img"
+        );
+        assert_eq!(
+            decode_file_name("kernel%20v1.0.0.img").unwrap(),
+            "kernel v1.0.0.img"
+        );
+        assert_eq!(
+            decode_file_name("..kernel.img").unwrap_err(),
+            PathRejection::Hidden
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resolve_symlinks() {
+        let base = std::env::temp_dir().join(format!("safe-path-{}", uuid::Uuid::new_v4()));
+        let root = base.join("kernels");
+        fs::create_dir_all(root.join("subdir")).await.unwrap();
+        fs::write(root.join("kernel.img"), b"kernel").await.unwrap();
+        fs::write(base.join("secret"), b"secret").await.unwrap();
+        fs::symlink(root.join("kernel.img"), root.join("inside.img"))
+            .await
+            .unwrap();
+        fs::symlink(base.join("secret"), root.join("outside.img"))
+            .await
+            .unwrap();
+        fs::symlink("/", root.join("rootfs")).await.unwrap();
+        fs::symlink(root.join("missing"), root.join("dangling.img"))
+            .await
+            .unwrap();
+
+        let resolve = |name: &'static str, policy| {
+            let root = root.clone();
+            async move {
+                resolve_in(&root, name, policy)
+                    .await
+                    .map(|resolved| resolved.path)
+            }
+        };
+
+        let canonical_root = fs::canonicalize(&root).await.unwrap();
+        assert_eq!(
+            resolve("kernel.img", SymlinkPolicy::Deny).await,
+            Ok(canonical_root.join("kernel.img"))
+        );
+        assert_eq!(
+            resolve("inside.img", SymlinkPolicy::Deny).await,
+            Err(PathRejection::Symlink)
+        );
+        assert_eq!(
+            resolve("inside.img", SymlinkPolicy::Within).await,
+            Ok(canonical_root.join("kernel.img"))
+        );
+        assert_eq!(
+            resolve("outside.img", SymlinkPolicy::Within).await,
+            Err(PathRejection::Escapes)
+        );
+        assert!(resolve("outside.img", SymlinkPolicy::Follow).await.is_ok());
+        assert_eq!(
+            resolve("rootfs", SymlinkPolicy::Follow).await,
+            Err(PathRejection::NotAFile)
+        );
+        assert_eq!(
+            resolve("subdir", SymlinkPolicy::Within).await,
+            Err(PathRejection::NotAFile)
+        );
+        assert_eq!(
+            resolve("dangling.img", SymlinkPolicy::Follow).await,
+            Err(PathRejection::NotFound)
+        );
+        assert_eq!(
+            resolve("missing.img", SymlinkPolicy::Within).await,
+            Err(PathRejection::NotFound)
+        );
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use crate::config::Signing;
+use crate::metadata::{KernelInfo, ReleaseSignature};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+pub struct ReleaseSigner {
+    key: SigningKey,
+}
+
+impl ReleaseSigner {
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    // Private keys are stored as the base64-encoded 32-byte Ed25519 seed
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read signing key {}", path.display()))?;
+        let seed: [u8; 32] = BASE64
+            .decode(content.trim())?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Signing key must be 32 bytes: {}", path.display()))?;
+        Ok(Self {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub async fn save<P: AsRef<Path>>(&self, private_path: P, public_path: P) -> Result<()> {
+        let private_path = private_path.as_ref();
+        let encoded = BASE64.encode(self.key.to_bytes());
+
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options
+            .open(private_path)
+            .await
+            .with_context(|| format!("Failed to create {}", private_path.display()))?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, format!("{}\n", encoded).as_bytes()).await?;
+
+        let public = format!("{}\n", public_key_base64(&self.verifying_key()));
+        tokio::fs::write(public_path, public).await?;
+        Ok(())
+    }
+
+    // The configured signing key, refused unless it matches the configured
+    // public key, since devices would fail to verify what it signs
+    pub async fn load_configured(signing: &Signing) -> Result<(Self, VerifyingKey)> {
+        let signer = Self::load(&signing.private_key).await?;
+        let public_key = load_public_key(&signing.public_key).await?;
+        if signer.verifying_key() != public_key {
+            return Err(anyhow::anyhow!(
+                "Signing key {} does not match public key {}",
+                signing.private_key,
+                signing.public_key
+            ));
+        }
+        Ok((signer, public_key))
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    // Sign both the image digest and the serialized release metadata
+    pub fn sign_release(&self, kernel_info: &KernelInfo) -> Result<ReleaseSignature> {
+        let digest = image_digest(&kernel_info.checksum)?;
+        let payload = kernel_info.signing_payload()?;
+
+        Ok(ReleaseSignature {
+            algorithm: "ed25519".to_string(),
+            key_fingerprint: key_fingerprint(&self.verifying_key()),
+            image_signature: BASE64.encode(self.key.sign(&digest).to_bytes()),
+            metadata_signature: BASE64.encode(self.key.sign(&payload).to_bytes()),
+        })
+    }
+}
+
+pub async fn load_public_key<P: AsRef<Path>>(path: P) -> Result<VerifyingKey> {
+    let path = path.as_ref();
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read public key {}", path.display()))?;
+    let bytes: [u8; 32] = BASE64
+        .decode(content.trim())?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Public key must be 32 bytes: {}", path.display()))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+pub fn public_key_base64(key: &VerifyingKey) -> String {
+    BASE64.encode(key.as_bytes())
+}
+
+// Fingerprint devices use to pick the right key: sha256 over the raw public key
+pub fn key_fingerprint(key: &VerifyingKey) -> String {
+    format!("sha256:{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// Verify a release the same way a device would, offline with only the public key
+pub fn verify_release(kernel_info: &KernelInfo, key: &VerifyingKey) -> Result<()> {
+    let signature = kernel_info
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Release {} is not signed", kernel_info.version))?;
+
+    let image_signature = decode_signature(&signature.image_signature)?;
+    key.verify(&image_digest(&kernel_info.checksum)?, &image_signature)
+        .context("Image signature does not match")?;
+
+    let metadata_signature = decode_signature(&signature.metadata_signature)?;
+    key.verify(&kernel_info.signing_payload()?, &metadata_signature)
+        .context("Metadata signature does not match")?;
+
+    Ok(())
+}
+
+// The image signature covers the raw 32-byte sha256 digest of the kernel file
+fn image_digest(checksum: &str) -> Result<Vec<u8>> {
+    let hex = checksum
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow::anyhow!("Unsupported checksum format: {}", checksum))?;
+    if hex.len() != 64 {
+        return Err(anyhow::anyhow!("Invalid sha256 checksum: {}", checksum));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow::anyhow!("Invalid sha256 checksum: {}", checksum))
+}
+
+fn decode_signature(encoded: &str) -> Result<Signature> {
+    let bytes: [u8; 64] = BASE64
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Signature must be 64 bytes"))?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify_release() {
+        let signer = ReleaseSigner::generate();
+        let mut kernel_info = KernelInfo::new(
+            "1.0.0".to_string(),
+            "kernel-v1.0.0.img".to_string(),
+            11,
+            "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_string(),
+            "Test kernel".to_string(),
+        );
+        kernel_info.signature = Some(signer.sign_release(&kernel_info).unwrap());
+        assert!(verify_release(&kernel_info, &signer.verifying_key()).is_ok());
+
+        kernel_info.description = "Tampered".to_string();
+        assert!(verify_release(&kernel_info, &signer.verifying_key()).is_err());
+    }
+}
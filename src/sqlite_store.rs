@@ -0,0 +1,204 @@
+use crate::metadata::{KernelInfo, VersionHistory};
+use crate::metadata_store::{MetadataStore, StoreBackend};
+use crate::rollout::{ReleaseRollout, RolloutState};
+use anyhow::Result;
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Schema changes, applied in order. The database's user_version records how
+// many have run, so never edit or reorder a migration once released.
+const MIGRATIONS: &[&str] = &[
+    // 1: releases are stored as their JSON metadata, in history order
+    "CREATE TABLE releases (
+        version TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE channel_heads (
+        channel TEXT PRIMARY KEY,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE pinned_channels (
+        channel TEXT PRIMARY KEY,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        latest TEXT NOT NULL
+    );
+    CREATE TABLE rollouts (
+        version TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );",
+];
+
+// Embedded SQLite database holding the same data as the JSON files. Each call
+// opens its own connection on a blocking thread; the schema is migrated on open.
+pub struct SqliteStore {
+    path: PathBuf,
+}
+
+impl SqliteStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut conn = Connection::open(&path)?;
+            conn.busy_timeout(Duration::from_secs(5))?;
+            migrate(&mut conn)
+                .map_err(|e| anyhow::anyhow!("Cannot migrate {}: {}", path.display(), e))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let applied: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if applied > MIGRATIONS.len() {
+        return Err(anyhow::anyhow!(
+            "database schema version {} is newer than this server supports ({})",
+            applied,
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl MetadataStore for SqliteStore {
+    fn backend(&self) -> StoreBackend {
+        StoreBackend::Sqlite
+    }
+
+    async fn load_history(&self) -> Result<VersionHistory> {
+        self.with_connection(|conn| {
+            let tx = conn.transaction()?;
+            let Some(latest) = tx
+                .query_row("SELECT latest FROM history WHERE id = 1", [], |row| {
+                    row.get::<_, String>(0)
+                })
+                .optional()?
+            else {
+                return Ok(VersionHistory::empty());
+            };
+
+            let mut history = VersionHistory::empty();
+            history.latest = latest;
+            let mut releases =
+                tx.prepare("SELECT version, data FROM releases ORDER BY position")?;
+            for row in releases.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })? {
+                let (version, data) = row?;
+                let kernel = serde_json::from_str::<KernelInfo>(&data)
+                    .map_err(|e| anyhow::anyhow!("Release {} is corrupt: {}", version, e))?;
+                history.versions.push(kernel);
+            }
+
+            let mut heads = tx.prepare("SELECT channel, version FROM channel_heads")?;
+            for row in heads.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (channel, version) = row?;
+                history.channels.insert(channel, version);
+            }
+
+            let mut pins = tx.prepare("SELECT channel, version FROM pinned_channels")?;
+            for row in pins.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (channel, version) = row?;
+                history.pinned.insert(channel, version);
+            }
+            Ok(history)
+        })
+        .await
+    }
+
+    async fn load_rollouts(&self) -> Result<RolloutState> {
+        self.with_connection(|conn| {
+            let mut rollouts = RolloutState::default();
+            let mut statement = conn.prepare("SELECT version, data FROM rollouts")?;
+            for row in statement.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })? {
+                let (version, data) = row?;
+                let rollout = serde_json::from_str::<ReleaseRollout>(&data).map_err(|e| {
+                    anyhow::anyhow!("Rollout state of {} is corrupt: {}", version, e)
+                })?;
+                rollouts.releases.insert(version, rollout);
+            }
+            Ok(rollouts)
+        })
+        .await
+    }
+
+    async fn save(
+        &self,
+        history: Option<&VersionHistory>,
+        rollouts: Option<&RolloutState>,
+    ) -> Result<()> {
+        let history = history.cloned();
+        let rollouts = rollouts.cloned();
+        self.with_connection(move |conn| {
+            // Both parts in one transaction: readers see all of a change or none
+            let tx = conn.transaction()?;
+            if let Some(rollouts) = rollouts {
+                tx.execute("DELETE FROM rollouts", [])?;
+                for (version, rollout) in &rollouts.releases {
+                    tx.execute(
+                        "INSERT INTO rollouts (version, data) VALUES (?1, ?2)",
+                        params![version, serde_json::to_string(rollout)?],
+                    )?;
+                }
+            }
+
+            if let Some(history) = history {
+                tx.execute("DELETE FROM releases", [])?;
+                tx.execute("DELETE FROM channel_heads", [])?;
+                tx.execute("DELETE FROM pinned_channels", [])?;
+                for (position, kernel) in history.versions.iter().enumerate() {
+                    tx.execute(
+                        "INSERT INTO releases (version, position, data) VALUES (?1, ?2, ?3)",
+                        params![kernel.version, position, serde_json::to_string(kernel)?],
+                    )?;
+                }
+                for (channel, version) in &history.channels {
+                    tx.execute(
+                        "INSERT INTO channel_heads (channel, version) VALUES (?1, ?2)",
+                        params![channel, version],
+                    )?;
+                }
+                for (channel, version) in &history.pinned {
+                    tx.execute(
+                        "INSERT INTO pinned_channels (channel, version) VALUES (?1, ?2)",
+                        params![channel, version],
+                    )?;
+                }
+                tx.execute(
+                    "INSERT OR REPLACE INTO history (id, latest) VALUES (1, ?1)",
+                    params![history.latest],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
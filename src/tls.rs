@@ -0,0 +1,370 @@
+use crate::config::{ClientAuth, Tls};
+use crate::device_registry::validate_device_id;
+use anyhow::{Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// The device a client certificate was issued to. Requests on a connection that
+// presented one carry it, and it takes precedence over any device_id they claim.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub device_id: String,
+}
+
+// The TLS settings built from [tls], swapped in place when the certificate,
+// device CA or CRL is renewed. New handshakes use the new settings;
+// established connections are unaffected.
+#[derive(Debug)]
+pub struct CertStore {
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl CertStore {
+    pub fn load(tls: &Tls) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new(build_server_config(tls)?),
+        })
+    }
+
+    // Files that fail to load leave the current settings in place
+    pub fn reload(&self, tls: &Tls) -> Result<()> {
+        let server_config = build_server_config(tls)?;
+        *self.current.write().unwrap() = server_config;
+        info!("Loaded TLS certificate {}", tls.cert);
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+}
+
+fn build_server_config(tls: &Tls) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &tls.client_auth {
+        Some(client_auth) => {
+            builder.with_client_cert_verifier(client_verifier(client_auth, provider)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let (chain, key) = load_certificate(tls)?;
+    let mut config = builder
+        .with_single_cert(chain, key)
+        .with_context(|| format!("{} does not match the key in {}", tls.cert, tls.key))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Certificate chain (leaf first, then the optional CA chain) and private key, in PEM
+fn load_certificate(tls: &Tls) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let mut chain = read_certificates(Path::new(&tls.cert))?;
+    if chain.is_empty() {
+        return Err(anyhow::anyhow!("No certificate found in {}", tls.cert));
+    }
+    if let Some(ca_chain) = &tls.ca_chain {
+        chain.extend(read_certificates(Path::new(ca_chain))?);
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| anyhow::anyhow!("Cannot read private key {}: {}", tls.key, e))?;
+    Ok((chain, key))
+}
+
+// Devices must present a certificate issued by the device CA (or may present
+// none, when not required). Certificates listed in the CRL are refused.
+fn client_verifier(
+    client_auth: &ClientAuth,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certificates(Path::new(&client_auth.ca))? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid device CA certificate in {}", client_auth.ca))?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificate found in {}",
+            client_auth.ca
+        ));
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .only_check_end_entity_revocation();
+    if let Some(crl) = &client_auth.crl {
+        let crls = CertificateRevocationListDer::pem_file_iter(crl)
+            .and_then(|crls| crls.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow::anyhow!("Cannot read revocation list {}: {}", crl, e))?;
+        builder = builder.with_crls(crls);
+    }
+    if !client_auth.required {
+        builder = builder.allow_unauthenticated();
+    }
+    builder
+        .build()
+        .with_context(|| format!("Cannot use device CA {}", client_auth.ca))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Cannot read certificates from {}: {}", path.display(), e))
+}
+
+// The device ID a verified client certificate names: its subject CN, or else
+// its first DNS or URI subject alternative name
+pub fn client_identity(certificate: &CertificateDer<'_>) -> Result<ClientIdentity> {
+    let (_, cert) = X509Certificate::from_der(certificate)
+        .map_err(|e| anyhow::anyhow!("Cannot parse client certificate: {}", e))?;
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok().map(str::to_string));
+    let alt_name = || {
+        cert.subject_alternative_name()
+            .ok()
+            .flatten()
+            .and_then(|san| {
+                san.value.general_names.iter().find_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                })
+            })
+    };
+
+    let device_id = common_name
+        .or_else(alt_name)
+        .ok_or_else(|| anyhow::anyhow!("Client certificate names no device"))?;
+    validate_device_id(&device_id).map_err(|e| anyhow::anyhow!("Client certificate: {}", e))?;
+    Ok(ClientIdentity { device_id })
+}
+
+// Self-signed certificate and key for lab setups, in PEM
+pub fn generate_self_signed(hostnames: Vec<String>) -> Result<(String, String)> {
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(hostnames)?;
+    Ok((cert.pem(), key_pair.serialize_pem()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload_keeps_certificate_on_error() {
+        let dir = std::env::temp_dir().join(format!("tls-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let tls = Tls {
+            cert: dir.join("cert.pem").to_string_lossy().to_string(),
+            key: dir.join("key.pem").to_string_lossy().to_string(),
+            ca_chain: None,
+            client_auth: None,
+        };
+
+        let (cert, key) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        tokio::fs::write(&tls.cert, &cert).await.unwrap();
+        tokio::fs::write(&tls.key, &key).await.unwrap();
+        let store = CertStore::load(&tls).unwrap();
+        let first = store.current.read().unwrap().clone();
+
+        // A renewed certificate with a key that doesn't match is rejected
+        let (other_cert, _) = generate_self_signed(vec!["localhost".to_string()]).unwrap();
+        tokio::fs::write(&tls.cert, &other_cert).await.unwrap();
+        assert!(store.reload(&tls).is_err());
+        assert!(Arc::ptr_eq(&first, &store.current.read().unwrap()));
+
+        let (cert, key) = generate_self_signed(vec!["ota.local".to_string()]).unwrap();
+        tokio::fs::write(&tls.cert, &cert).await.unwrap();
+        tokio::fs::write(&tls.key, &key).await.unwrap();
+        store.reload(&tls).unwrap();
+        assert!(!Arc::ptr_eq(&first, &store.current.read().unwrap()));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let certificate = |common_name: Option<&str>, alt_names: Vec<String>| {
+            let mut params = rcgen::CertificateParams::new(alt_names).unwrap();
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            if let Some(common_name) = common_name {
+                params
+                    .distinguished_name
+                    .push(rcgen::DnType::CommonName, common_name);
+            }
+            let key_pair = rcgen::KeyPair::generate().unwrap();
+            params.self_signed(&key_pair).unwrap().der().clone()
+        };
+
+        // The CN wins over alternative names
+        let cert = certificate(Some("device-0042"), vec!["device-0042.fleet".to_string()]);
+        assert_eq!(client_identity(&cert).unwrap().device_id, "device-0042");
+
+        let cert = certificate(None, vec!["device-0043.fleet".to_string()]);
+        assert_eq!(
+            client_identity(&cert).unwrap().device_id,
+            "device-0043.fleet"
+        );
+
+        let cert = certificate(None, vec![]);
+        assert!(client_identity(&cert).is_err());
+    }
+
+    struct Issued {
+        cert: rcgen::Certificate,
+        key: rcgen::KeyPair,
+    }
+
+    fn issue_ca(name: &str) -> Issued {
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            rcgen::KeyUsagePurpose::KeyCertSign,
+            rcgen::KeyUsagePurpose::CrlSign,
+        ];
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Issued { cert, key }
+    }
+
+    fn issue(
+        ca: &Issued,
+        name: &str,
+        serial: u64,
+        usage: rcgen::ExtendedKeyUsagePurpose,
+    ) -> Issued {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.serial_number = Some(serial.into());
+        params.extended_key_usages = vec![usage];
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+        Issued { cert, key }
+    }
+
+    // Whether the server accepts a handshake from a client presenting `device`
+    async fn handshake(store: &CertStore, server_ca: &Issued, device: Option<&Issued>) -> bool {
+        let mut roots = RootCertStore::empty();
+        roots.add(server_ca.cert.der().clone()).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+        let config = match device {
+            Some(device) => builder
+                .with_client_auth_cert(
+                    vec![device.cert.der().clone()],
+                    PrivateKeyDer::try_from(device.key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let (_, accepted) = tokio::join!(
+            connector.connect(name, client),
+            store.acceptor().accept(server)
+        );
+        accepted.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_handshake() {
+        let dir = std::env::temp_dir().join(format!("tls-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        let server_ca = issue_ca("Server CA");
+        let server = issue(
+            &server_ca,
+            "localhost",
+            1,
+            rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+        );
+        let device_ca = issue_ca("Device CA");
+        let good = issue(
+            &device_ca,
+            "device-0001",
+            2,
+            rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+        );
+        let revoked = issue(
+            &device_ca,
+            "device-0002",
+            3,
+            rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+        );
+        let stranger = issue(
+            &issue_ca("Other CA"),
+            "device-0003",
+            4,
+            rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+        );
+
+        let crl = rcgen::CertificateRevocationListParams {
+            this_update: rcgen::date_time_ymd(2024, 1, 1),
+            next_update: rcgen::date_time_ymd(2100, 1, 1),
+            crl_number: 1u64.into(),
+            issuing_distribution_point: None,
+            revoked_certs: vec![rcgen::RevokedCertParams {
+                serial_number: 3u64.into(),
+                revocation_time: rcgen::date_time_ymd(2024, 1, 1),
+                reason_code: None,
+                invalidity_date: None,
+            }],
+            key_identifier_method: rcgen::KeyIdMethod::Sha256,
+        }
+        .signed_by(&device_ca.cert, &device_ca.key)
+        .unwrap();
+
+        tokio::fs::write(path("cert.pem"), server.cert.pem())
+            .await
+            .unwrap();
+        tokio::fs::write(path("key.pem"), server.key.serialize_pem())
+            .await
+            .unwrap();
+        tokio::fs::write(path("device-ca.pem"), device_ca.cert.pem())
+            .await
+            .unwrap();
+        tokio::fs::write(path("devices.crl"), crl.pem().unwrap())
+            .await
+            .unwrap();
+        let tls = Tls {
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+            ca_chain: None,
+            client_auth: Some(ClientAuth {
+                ca: path("device-ca.pem"),
+                crl: Some(path("devices.crl")),
+                required: true,
+            }),
+        };
+        let store = CertStore::load(&tls).unwrap();
+
+        assert!(handshake(&store, &server_ca, Some(&good)).await);
+        assert!(!handshake(&store, &server_ca, Some(&revoked)).await);
+        assert!(!handshake(&store, &server_ca, Some(&stranger)).await);
+        assert!(!handshake(&store, &server_ca, None).await);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+use crate::compatibility::DeviceProfile;
+use crate::metadata::{ClientKernelInfo, DEFAULT_CHANNEL, KernelInfo, VersionHistory};
+use crate::rollout::RolloutState;
+use crate::versioning::{compare_versions, parse_version};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+// Update check sent by a device to POST /check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckRequest {
+    pub device_id: String,
+    pub current_version: String,
+    pub hardware_model: String,
+    #[serde(default)]
+    pub bootloader_version: Option<String>,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CheckResponse {
+    UpToDate { current_version: String },
+    UpdateAvailable { kernel: Box<ClientKernelInfo> },
+    Blocked { reason: String },
+}
+
+fn default_channel() -> String {
+    DEFAULT_CHANNEL.to_string()
+}
+
+// Decide what a device should do, given the current release history.
+// Kept free of I/O and warp so the policy can be tested on its own.
+pub fn check_for_update(
+    history: &VersionHistory,
+    rollouts: &RolloutState,
+    request: &CheckRequest,
+) -> CheckResponse {
+    let current = match parse_version(&request.current_version) {
+        Ok(version) => version,
+        Err(_) => {
+            return CheckResponse::Blocked {
+                reason: format!(
+                    "Current version '{}' is not a valid semantic version",
+                    request.current_version
+                ),
+            };
+        }
+    };
+
+    let Some(head) = history.channel_head(&request.channel) else {
+        return CheckResponse::Blocked {
+            reason: format!("No release available on channel '{}'", request.channel),
+        };
+    };
+
+    if !is_newer(&head.version, &current) {
+        let device = request.device_profile();
+        return match select_release(history, rollouts, &request.channel, &device) {
+            Ok(kernel)
+                if must_downgrade(history, &request.channel, &request.current_version, kernel) =>
+            {
+                let mut kernel = kernel.to_client_format();
+                kernel.force_downgrade = true;
+                CheckResponse::UpdateAvailable {
+                    kernel: Box::new(kernel),
+                }
+            }
+            _ => CheckResponse::UpToDate {
+                current_version: request.current_version.clone(),
+            },
+        };
+    }
+
+    // A newer release exists. The device is blocked if it cannot run it, or just
+    // has to wait if it is outside the release's rollout.
+    let device = request.device_profile();
+    match select_release(history, rollouts, &request.channel, &device) {
+        Ok(kernel) if is_newer(&kernel.version, &current) => CheckResponse::UpdateAvailable {
+            kernel: Box::new(kernel.to_client_format()),
+        },
+        Ok(_) => match head
+            .compatibility
+            .as_ref()
+            .and_then(|compatibility| compatibility.check(&device).err())
+        {
+            Some(reason) => CheckResponse::Blocked {
+                reason: format!("Release {}: {}", head.version, reason),
+            },
+            None => CheckResponse::UpToDate {
+                current_version: request.current_version.clone(),
+            },
+        },
+        Err(reason) => CheckResponse::Blocked { reason },
+    }
+}
+
+// Highest release on a channel that the device is eligible for and rolled out
+// to. Releases above the channel head (e.g. when an older release is pinned)
+// are never offered.
+pub fn select_release<'a>(
+    history: &'a VersionHistory,
+    rollouts: &RolloutState,
+    channel: &str,
+    device: &DeviceProfile,
+) -> Result<&'a KernelInfo, String> {
+    let head = history
+        .channel_head(channel)
+        .ok_or_else(|| format!("No release available on channel '{}'", channel))?;
+
+    let mut first_rejection = None;
+    for kernel in history.sorted_versions().into_iter().rev() {
+        let in_channel = kernel.channels.iter().any(|c| c == channel);
+        if !in_channel
+            || !kernel.is_offered()
+            || compare_versions(&kernel.version, &head.version) == Ordering::Greater
+        {
+            continue;
+        }
+        if !rollouts.includes(&kernel.version, device.device_id) {
+            continue;
+        }
+
+        let eligible = kernel
+            .compatibility
+            .as_ref()
+            .map_or(Ok(()), |compatibility| compatibility.check(device));
+        match eligible {
+            Ok(()) => return Ok(kernel),
+            Err(reason) => {
+                first_rejection
+                    .get_or_insert_with(|| format!("Release {}: {}", kernel.version, reason));
+            }
+        }
+    }
+
+    Err(first_rejection.unwrap_or_else(|| format!("No eligible release on channel '{}'", channel)))
+}
+
+// A device still running a release its channel was rolled back from has to
+// install the older release it is offered
+pub fn must_downgrade(
+    history: &VersionHistory,
+    channel: &str,
+    current_version: &str,
+    offered: &KernelInfo,
+) -> bool {
+    history
+        .versions
+        .iter()
+        .filter(|kernel| compare_versions(&kernel.version, current_version) == Ordering::Equal)
+        .any(|current| current.rolled_back_on(channel).is_some())
+        && compare_versions(&offered.version, current_version) == Ordering::Less
+}
+
+impl CheckRequest {
+    pub fn device_profile(&self) -> DeviceProfile<'_> {
+        DeviceProfile {
+            device_id: Some(&self.device_id),
+            hardware_model: Some(&self.hardware_model),
+            bootloader_version: self.bootloader_version.as_deref(),
+            current_version: Some(&self.current_version),
+        }
+    }
+}
+
+fn is_newer(version: &str, current: &Version) -> bool {
+    parse_version(version)
+        .map(|version| version.cmp_precedence(current) == Ordering::Greater)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compatibility::Compatibility;
+    use crate::metadata::{ReleaseStatus, Rollback};
+
+    fn history(versions: &[(&str, &str)]) -> VersionHistory {
+        let mut history = VersionHistory::empty();
+        for (version, channel) in versions {
+            let mut kernel = KernelInfo::new(
+                version.to_string(),
+                format!("kernel-v{}.img", version),
+                1,
+                "sha256:00".to_string(),
+                String::new(),
+            );
+            kernel.channels = vec![channel.to_string()];
+            history.versions.push(kernel);
+        }
+        history.recompute_heads();
+        history
+    }
+
+    fn request(current_version: &str, channel: &str) -> CheckRequest {
+        CheckRequest {
+            device_id: "device-1".to_string(),
+            current_version: current_version.to_string(),
+            hardware_model: "board-a".to_string(),
+            bootloader_version: None,
+            channel: channel.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_for_update() {
+        let history = history(&[("1.0.0", "stable"), ("2.0.0", "stable"), ("2.1.0", "beta")]);
+
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("1.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => assert_eq!(kernel.latest_version, "2.0.0"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("2.0.0", "beta"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => assert_eq!(kernel.latest_version, "2.1.0"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("2.0.0", "stable")
+            ),
+            CheckResponse::UpToDate { .. }
+        ));
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("2.1.0", "stable")
+            ),
+            CheckResponse::UpToDate { .. }
+        ));
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("2.0.0", "nightly")
+            ),
+            CheckResponse::Blocked { .. }
+        ));
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("not-a-version", "stable")
+            ),
+            CheckResponse::Blocked { .. }
+        ));
+    }
+
+    #[test]
+    fn test_check_respects_compatibility() {
+        let mut history = history(&[
+            ("1.0.0", "stable"),
+            ("1.0.2", "stable"),
+            ("2.0.0", "stable"),
+        ]);
+        history.versions[2].compatibility = Some(Compatibility {
+            boards: vec!["rev-c".to_string()],
+            ..Compatibility::default()
+        });
+
+        // rev-b boards fall back to the newest release they can run
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("1.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => assert_eq!(kernel.latest_version, "1.0.2"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("1.0.2", "stable")
+            ),
+            CheckResponse::Blocked { .. }
+        ));
+
+        history.versions[1].compatibility = history.versions[2].compatibility.clone();
+        history.versions[0].compatibility = history.versions[2].compatibility.clone();
+        assert!(matches!(
+            check_for_update(
+                &history,
+                &RolloutState::default(),
+                &request("0.9.0", "stable")
+            ),
+            CheckResponse::Blocked { .. }
+        ));
+    }
+
+    #[test]
+    fn test_check_respects_rollout() {
+        let history = history(&[("1.0.0", "stable"), ("2.0.0", "stable")]);
+        let mut rollouts = RolloutState::default();
+        rollouts.set_percentage("2.0.0", 0);
+
+        assert!(matches!(
+            check_for_update(&history, &rollouts, &request("1.0.0", "stable")),
+            CheckResponse::UpToDate { .. }
+        ));
+
+        rollouts.set_percentage("2.0.0", 100);
+        assert!(matches!(
+            check_for_update(&history, &rollouts, &request("1.0.0", "stable")),
+            CheckResponse::UpdateAvailable { .. }
+        ));
+    }
+
+    #[test]
+    fn test_check_skips_yanked() {
+        let mut history = history(&[
+            ("1.0.0", "stable"),
+            ("1.0.2", "stable"),
+            ("2.0.0", "stable"),
+        ]);
+        history.versions[2].status = ReleaseStatus::Yanked;
+        history.recompute_heads();
+        assert_eq!(history.latest, "1.0.2");
+
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("1.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => assert_eq!(kernel.latest_version, "1.0.2"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_offers_deprecated() {
+        let mut history = history(&[("1.0.0", "stable"), ("1.0.2", "stable")]);
+        history.versions[1].status = ReleaseStatus::Deprecated;
+        history.recompute_heads();
+        assert_eq!(history.latest, "1.0.2");
+
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("1.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => {
+                assert_eq!(kernel.latest_version, "1.0.2");
+                assert!(kernel.deprecated);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_forces_downgrade() {
+        let mut history = history(&[("1.0.2", "stable"), ("2.0.0", "stable")]);
+        history.versions[1].rollbacks.push(Rollback {
+            channel: "stable".to_string(),
+            to_version: "1.0.2".to_string(),
+            by: "ops".to_string(),
+            reason: "boot loops".to_string(),
+            at: chrono::Utc::now(),
+        });
+        history
+            .pinned
+            .insert("stable".to_string(), "1.0.2".to_string());
+        history.recompute_heads();
+
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("2.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => {
+                assert_eq!(kernel.latest_version, "1.0.2");
+                assert!(kernel.force_downgrade);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Devices below the rollback target upgrade as usual
+        match check_for_update(
+            &history,
+            &RolloutState::default(),
+            &request("1.0.0", "stable"),
+        ) {
+            CheckResponse::UpdateAvailable { kernel } => assert!(!kernel.force_downgrade),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::atomic_file::sync_parent_dir;
+use crate::checksum::calculate_file_checksum;
+use anyhow::Result;
+use bytes::Buf;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::{Stream, StreamExt};
+
+// An image written to kernels_dir
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StoredFile {
+    pub file_name: String,
+    pub file_size: u64,
+    pub checksum: String,
+    // False if an identical file was already there
+    pub created: bool,
+}
+
+// The target name is taken by a file with different content
+#[derive(Debug)]
+pub struct ContentConflict {
+    pub file_name: String,
+}
+
+impl std::fmt::Display for ContentConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} already exists with different content",
+            self.file_name
+        )
+    }
+}
+
+impl std::error::Error for ContentConflict {}
+
+// Accepts "sha256:<hex>" or bare hex, as build systems print either
+pub fn normalize_checksum(checksum: &str) -> Result<String> {
+    let hex = checksum.strip_prefix("sha256:").unwrap_or(checksum);
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("Invalid sha256 checksum: {}", checksum));
+    }
+    Ok(format!("sha256:{}", hex.to_ascii_lowercase()))
+}
+
+// File name for an image from a template such as "kernel-v{version}.img"
+pub fn render_file_name(template: &str, version: &str, channel: &str) -> Result<String> {
+    let file_name = template
+        .replace("{version}", version.trim_start_matches('v'))
+        .replace("{channel}", channel);
+    if file_name.contains(['{', '}']) {
+        return Err(anyhow::anyhow!(
+            "Unknown placeholder in file name template {:?} (use {{version}} and {{channel}})",
+            template
+        ));
+    }
+    validate_file_name(&file_name).map_err(anyhow::Error::msg)?;
+    Ok(file_name)
+}
+
+// Image names become paths in kernels_dir and URLs under /kernels
+pub fn validate_file_name(file_name: &str) -> Result<(), String> {
+    let valid = !file_name.is_empty()
+        && file_name.len() <= 255
+        && !file_name.starts_with('.')
+        && !file_name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control());
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid kernel file name: {:?}", file_name))
+    }
+}
+
+// Stream an image into dir/file_name, hashing it while it is written to a
+// temp file. An image that does not match `expected_checksum` is dropped. The
+// temp file is linked into place only if the name is free, so an existing file
+// is never replaced: identical content is accepted as is, different content is
+// a ContentConflict.
+pub async fn store_stream<S, B, E>(
+    dir: &Path,
+    file_name: &str,
+    stream: S,
+    max_size: u64,
+    expected_checksum: Option<&str>,
+) -> Result<StoredFile>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    validate_file_name(file_name).map_err(anyhow::Error::msg)?;
+    let expected_checksum = expected_checksum.map(normalize_checksum).transpose()?;
+    fs::create_dir_all(dir).await?;
+    let temp_path = dir.join(format!(".{}.upload-{}", file_name, uuid::Uuid::new_v4()));
+
+    let result = async {
+        let (file_size, checksum) = write_and_hash(&temp_path, stream, max_size).await?;
+        if let Some(expected) = &expected_checksum
+            && expected != &checksum
+        {
+            return Err(anyhow::anyhow!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                file_name,
+                expected,
+                checksum
+            ));
+        }
+        let target = dir.join(file_name);
+        let created = match fs::hard_link(&temp_path, &target).await {
+            Ok(()) => {
+                sync_parent_dir(&target).await?;
+                true
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if calculate_file_checksum(&target).await? != checksum {
+                    return Err(ContentConflict {
+                        file_name: file_name.to_string(),
+                    }
+                    .into());
+                }
+                false
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(StoredFile {
+            file_name: file_name.to_string(),
+            file_size,
+            checksum,
+            created,
+        })
+    }
+    .await;
+
+    let _ = fs::remove_file(&temp_path).await;
+    result
+}
+
+async fn write_and_hash<S, B, E>(path: &Path, stream: S, max_size: u64) -> Result<(u64, String)>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut file = fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut file_size = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk?;
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            file_size += bytes.len() as u64;
+            if file_size > max_size {
+                return Err(anyhow::anyhow!(
+                    "Image is larger than the {} byte limit",
+                    max_size
+                ));
+            }
+            hasher.update(bytes);
+            file.write_all(bytes).await?;
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+    file.sync_all().await?;
+
+    Ok((file_size, format!("sha256:{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn chunks(parts: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+        tokio_stream::iter(
+            parts
+                .iter()
+                .map(|part| Ok(Bytes::from_static(part)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_store_stream() {
+        let dir = std::env::temp_dir().join(format!("upload-{}", uuid::Uuid::new_v4()));
+
+        let stored = store_stream(
+            &dir,
+            "kernel.img",
+            chunks(&[b"hello ", b"world"]),
+            1024,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(stored.created);
+        assert_eq!(stored.file_size, 11);
+        assert_eq!(
+            stored.checksum,
+            calculate_file_checksum(dir.join("kernel.img"))
+                .await
+                .unwrap()
+        );
+
+        // The same content again is fine; different content never replaces it
+        let again = store_stream(&dir, "kernel.img", chunks(&[b"hello world"]), 1024, None)
+            .await
+            .unwrap();
+        assert!(!again.created);
+        let conflict = store_stream(&dir, "kernel.img", chunks(&[b"other"]), 1024, None)
+            .await
+            .unwrap_err();
+        assert!(conflict.downcast_ref::<ContentConflict>().is_some());
+        assert_eq!(
+            fs::read(dir.join("kernel.img")).await.unwrap(),
+            b"hello world"
+        );
+
+        assert!(
+            store_stream(&dir, "big.img", chunks(&[b"0123456789"]), 4, None)
+                .await
+                .is_err()
+        );
+        // A corrupted artifact never lands under its name
+        let wrong = format!("sha256:{}", "0".repeat(64));
+        assert!(
+            store_stream(&dir, "other.img", chunks(&[b"x"]), 4, Some(&wrong))
+                .await
+                .is_err()
+        );
+        let expected = stored.checksum.trim_start_matches("sha256:").to_uppercase();
+        assert!(
+            store_stream(
+                &dir,
+                "kernel.img",
+                chunks(&[b"hello world"]),
+                1024,
+                Some(&expected)
+            )
+            .await
+            .is_ok()
+        );
+        assert!(
+            store_stream(&dir, "../escape.img", chunks(&[b"x"]), 4, None)
+                .await
+                .is_err()
+        );
+
+        // Nothing but the stored image is left behind
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        assert_eq!(names, vec!["kernel.img"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_render_file_name() {
+        assert_eq!(
+            render_file_name("kernel-v{version}.img", "v2.1.0", "stable").unwrap(),
+            "kernel-v2.1.0.img"
+        );
+        assert_eq!(
+            render_file_name("{channel}-{version}.img", "2.1.0-rc.1", "beta").unwrap(),
+            "beta-2.1.0-rc.1.img"
+        );
+        assert!(render_file_name("kernel-{sha}.img", "2.1.0", "stable").is_err());
+        assert!(render_file_name("../{version}.img", "2.1.0", "stable").is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use semver::Version;
+use std::cmp::Ordering;
+
+// Parse a kernel version as semver. A leading `v` (as in `v1.0.1`) is accepted.
+pub fn parse_version(version: &str) -> Result<Version, semver::Error> {
+    Version::parse(version.strip_prefix('v').unwrap_or(version))
+}
+
+// Order two kernel versions by semver precedence. Build metadata does not affect
+// precedence, and versions that fail to parse sort below every valid version.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (parse_version(a), parse_version(b)) {
+        (Ok(a), Ok(b)) => a.cmp_precedence(&b),
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("2.0.0", "1.0.2"), Ordering::Greater);
+        assert_eq!(compare_versions("1.10.0", "1.9.0"), Ordering::Greater);
+        assert_eq!(compare_versions("2.0.0-rc.1", "2.0.0"), Ordering::Less);
+        assert_eq!(
+            compare_versions("2.0.0-rc.2", "2.0.0-rc.10"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("1.0.0+build.1", "1.0.0+build.2"),
+            Ordering::Equal
+        );
+        assert_eq!(compare_versions("v1.0.1", "1.0.0"), Ordering::Greater);
+        assert_eq!(compare_versions("garbage", "0.0.1"), Ordering::Less);
+        assert!(parse_version("1.0").is_err());
+    }
+}